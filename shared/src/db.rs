@@ -1,7 +1,13 @@
-use anyhow::Result;
+use crate::media::Category;
+use anyhow::{bail, Result};
 use chrono::TimeZone;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use wither::bson::{doc, oid::ObjectId, Document};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wither::bson::{doc, oid::ObjectId, Bson, DateTime, Document};
 use wither::mongodb::options::ClientOptions;
 use wither::mongodb::*;
 use wither::prelude::*;
@@ -12,19 +18,59 @@ pub struct Stats {
     total_links: i64,
     total_opendirectories: i64,
     alive_opendirectories: i64,
+    /// Number of links in each category
+    #[serde(default)]
+    categories: BTreeMap<Category, i64>,
 }
 
 #[derive(Debug, Model, Serialize, Deserialize)]
 #[model(
     collection_name = "opendirectories",
     index(keys = r#"doc!{"url": 1}"#, options = r#"doc!{"unique": true}"#),
-    index(keys = r#"doc!{"unreachable": 1}"#)
+    index(keys = r#"doc!{"unreachable": 1}"#),
+    index(keys = r#"doc!{"last_checked": 1}"#)
 )]
 pub struct OpenDirectory {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub url: String,
     pub unreachable: i32,
+    pub last_checked: Option<DateTime>,
+    /// Share of sampled files that were reachable when the OD was last checked
+    #[serde(default)]
+    pub file_success_rate: Option<f64>,
+    /// Where the OD redirected to when it was last checked
+    #[serde(default)]
+    pub final_url: Option<String>,
+    /// `Some(true)` keeps the OD alive and `Some(false)` keeps it dead, regardless of checks
+    #[serde(default)]
+    pub pinned: Option<bool>,
+    /// Overrides the global dead OD threshold for this OD
+    #[serde(default)]
+    pub dead_threshold: Option<i32>,
+}
+
+impl OpenDirectory {
+    /// The number of failed checks after which this OD counts as dead
+    pub fn threshold(&self, default: i32) -> i32 {
+        self.dead_threshold.unwrap_or(default)
+    }
+
+    pub fn is_dead(&self, default_threshold: i32) -> bool {
+        match self.pinned {
+            Some(alive) => !alive,
+            None => self.unreachable >= self.threshold(default_threshold),
+        }
+    }
+}
+
+/// Settings which are shared between everything using the database
+#[derive(Debug, Model, Serialize, Deserialize)]
+#[model(collection_name = "settings")]
+pub struct Settings {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub dead_od_threshold: i32,
 }
 
 impl Migrating for OpenDirectory {
@@ -51,13 +97,102 @@ impl Migrating for OpenDirectory {
 #[derive(Debug, Model, Serialize, Deserialize)]
 #[model(
     index(keys = r#"doc!{"url": 1}"#, options = r#"doc!{"unique": true}"#),
-    index(keys = r#"doc!{"opendirectory": 1}"#)
+    index(keys = r#"doc!{"opendirectory": 1}"#),
+    index(keys = r#"doc!{"category": 1}"#)
 )]
 pub struct Link {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub opendirectory: String,
     pub url: String,
+    /// How often in a row this link failed when it was sampled
+    #[serde(default)]
+    pub missing: i32,
+    /// Size in bytes, if the scan could determine it
+    #[serde(default)]
+    pub size: Option<i64>,
+    #[serde(default)]
+    pub last_modified: Option<DateTime>,
+    /// Absent for links which haven't been classified yet, null if they're unclassifiable
+    #[serde(default)]
+    pub category: Option<Category>,
+}
+
+impl Link {
+    pub fn is_missing(&self) -> bool {
+        self.missing >= crate::MISSING_LINK_THRESHOLD
+    }
+}
+
+/// The TLS certificate a host presented when it was last checked.
+#[derive(Debug, Model, Serialize, Deserialize, Clone)]
+#[model(
+    collection_name = "certificates",
+    index(keys = r#"doc!{"host": 1}"#, options = r#"doc!{"unique": true}"#)
+)]
+pub struct Certificate {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub host: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: DateTime,
+    /// Whether the certificate chain and hostname verified successfully
+    pub valid: bool,
+    /// SHA-256 fingerprint
+    pub fingerprint: String,
+    /// The fingerprint before the last change
+    pub previous_fingerprint: Option<String>,
+    /// When the certificate last changed
+    pub changed: Option<DateTime>,
+    pub checked: DateTime,
+}
+
+impl Certificate {
+    pub fn expires_soon(&self) -> bool {
+        self.not_after.0
+            < chrono::Utc::now() + chrono::Duration::days(crate::CERT_EXPIRY_WARNING_DAYS)
+    }
+
+    pub fn changed_recently(&self) -> bool {
+        match &self.changed {
+            Some(changed) => {
+                changed.0
+                    > chrono::Utc::now() - chrono::Duration::days(crate::CERT_CHANGE_WARNING_DAYS)
+            }
+            None => false,
+        }
+    }
+}
+
+/// A pass of the link checker over all ODs. Unfinished runs are resumed after a restart.
+#[derive(Debug, Model, Serialize, Deserialize)]
+#[model(collection_name = "check_runs")]
+pub struct CheckRun {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub started: DateTime,
+    pub finished: Option<DateTime>,
+}
+
+/// An export of links to the search index. Unfinished exports are resumed from their checkpoint.
+#[derive(Debug, Model, Serialize, Deserialize)]
+#[model(collection_name = "export_runs")]
+pub struct ExportRun {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// The OD the export is limited to
+    pub opendirectory: Option<String>,
+    /// The export is limited to links added since then
+    pub since: Option<DateTime>,
+    /// The index a full export is rebuilding
+    pub index: Option<String>,
+    /// ID of the last exported link
+    pub checkpoint: Option<ObjectId>,
+    pub exported: i64,
+    pub failed: i64,
+    pub started: DateTime,
+    pub finished: Option<DateTime>,
 }
 
 #[derive(PartialEq)]
@@ -95,39 +230,364 @@ impl Migrating for Link {
     }
 }
 
+/// How long link counts per category are reused, since counting them scans all links
+const CATEGORY_COUNTS_TTL: Duration = Duration::from_secs(60 * 60);
+
+type CategoryCounts = BTreeMap<Category, i64>;
+
 #[derive(Clone)]
 pub struct Database {
     pub db: wither::mongodb::Database,
+    /// The number of failed checks after which an OD counts as dead, unless overridden per OD
+    pub dead_od_threshold: i32,
+    /// Last result of [Database::category_counts] and when it was counted
+    category_counts: Arc<Mutex<Option<(Instant, CategoryCounts)>>>,
 }
 
 impl Database {
     pub async fn new() -> Result<Self> {
+        Self::open("odcrawler-discovery").await
+    }
+
+    /// Connects to the database with this name, e.g. to keep tests apart.
+    pub async fn open(name: &str) -> Result<Self> {
         info!("Connecting to database");
         let mut options = ClientOptions::default();
         options.app_name = Some("odcrawler-discovery".to_string());
-        let db = Client::with_options(options)?.database("odcrawler-discovery");
+        let db = Client::with_options(options)?.database(name);
 
         OpenDirectory::sync(&db).await?;
         Link::sync(&db).await?;
+        CheckRun::sync(&db).await?;
+        ExportRun::sync(&db).await?;
+        Certificate::sync(&db).await?;
         OpenDirectory::migrate(&db).await?;
         Link::migrate(&db).await?;
 
-        Ok(Self { db })
+        let dead_od_threshold = Self::load_dead_od_threshold(&db).await?;
+
+        Ok(Self {
+            db,
+            dead_od_threshold,
+            category_counts: Arc::default(),
+        })
+    }
+
+    async fn load_dead_od_threshold(db: &wither::mongodb::Database) -> Result<i32> {
+        Ok(Settings::find_one(db, None, None)
+            .await?
+            .map(|s| s.dead_od_threshold)
+            .unwrap_or(crate::DEFAULT_DEAD_OD_THRESHOLD))
+    }
+
+    /// Returns a handle with the settings re-read from the database, for long-running processes
+    /// which should pick up a threshold changed by the crawler.
+    pub async fn refreshed(&self) -> Result<Self> {
+        Ok(Self {
+            db: self.db.clone(),
+            dead_od_threshold: Self::load_dead_od_threshold(&self.db).await?,
+            category_counts: self.category_counts.clone(),
+        })
+    }
+
+    /// Persists a new dead OD threshold and returns the previous one.
+    pub async fn set_dead_od_threshold(&mut self, threshold: i32) -> Result<i32> {
+        if threshold <= 0 {
+            bail!("The dead OD threshold must be positive, got {}", threshold);
+        }
+        let previous = self.dead_od_threshold;
+        Settings::collection(&self.db)
+            .update_one(
+                doc! {},
+                doc! {"$set": {"dead_od_threshold": threshold}},
+                options::UpdateOptions::builder().upsert(true).build(),
+            )
+            .await?;
+        self.dead_od_threshold = threshold;
+        Ok(previous)
+    }
+
+    /// Matches all ODs that are alive, taking per-OD overrides into account.
+    fn alive_filter(&self) -> Document {
+        self.alive_filter_at("")
+    }
+
+    /// Like [Database::alive_filter], for ODs embedded in documents at `prefix` (e.g. `"od."`).
+    fn alive_filter_at(&self, prefix: &str) -> Document {
+        let field = |name: &str| format!("{}{}", prefix, name);
+        doc! {"$or": [
+            {(field("pinned")): true},
+            {
+                (field("pinned")): {"$ne": false},
+                "$expr": {"$lt": [
+                    (format!("${}", field("unreachable"))),
+                    {"$ifNull": [(format!("${}", field("dead_threshold"))), self.dead_od_threshold]}
+                ]}
+            }
+        ]}
     }
 
     pub async fn get_opendirectories(&self, dead_ods: bool) -> Result<ModelCursor<OpenDirectory>> {
         let doc = if dead_ods {
             doc! {}
         } else {
-            doc! { "unreachable": doc! { "$lt": crate::DEAD_OD_THRESHOLD} }
+            self.alive_filter()
         };
         Ok(OpenDirectory::find(&self.db, doc, None).await?)
     }
 
+    pub async fn get_opendirectory(&self, url: &str) -> Result<Option<OpenDirectory>> {
+        Ok(OpenDirectory::find_one(&self.db, doc! {"url": url}, None).await?)
+    }
+
+    /// Returns the ODs with these URLs, skipping URLs without one.
+    pub async fn get_opendirectories_by_urls(&self, urls: &[&str]) -> Result<Vec<OpenDirectory>> {
+        Ok(OpenDirectory::find(&self.db, doc! {"url": {"$in": urls}}, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    /// Saves the outcome of checking an OD. Only the fields a check determines are written, so
+    /// pins and thresholds changed while it was checked are kept.
+    pub async fn save_check_result(&self, od: &OpenDirectory) -> Result<()> {
+        let id = match &od.id {
+            Some(id) => id.clone(),
+            None => bail!("OD {} wasn't saved yet", od.url),
+        };
+        let update = doc! {"$set": {
+            "unreachable": od.unreachable,
+            "last_checked": od.last_checked.as_ref().map_or(Bson::Null, |d| Bson::from(d.0)),
+            "final_url": od.final_url.as_deref().map_or(Bson::Null, Bson::from),
+            "file_success_rate": od.file_success_rate.map_or(Bson::Null, Bson::from),
+        }};
+        OpenDirectory::collection(&self.db)
+            .update_one(doc! {"_id": id}, update, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Saves the outcome of sampling a link. A category is only written if one was found, so
+    /// links which weren't classified yet aren't marked as unclassifiable.
+    pub async fn save_sample_result(&self, link: &Link) -> Result<()> {
+        let id = match &link.id {
+            Some(id) => id.clone(),
+            None => bail!("Link {} wasn't saved yet", link.url),
+        };
+        let mut set = doc! {"missing": link.missing};
+        if let Some(category) = link.category {
+            set.insert("category", category.as_str());
+        }
+        Link::collection(&self.db)
+            .update_one(doc! {"_id": id}, doc! {"$set": set}, None)
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the unfinished check run, or starts a new one.
+    pub async fn current_check_run(&self) -> Result<CheckRun> {
+        if let Some(run) = CheckRun::find_one(&self.db, doc! {"finished": null}, None).await? {
+            return Ok(run);
+        }
+        let mut run = CheckRun {
+            id: None,
+            started: chrono::Utc::now().into(),
+            finished: None,
+        };
+        run.save(&self.db, None).await?;
+        Ok(run)
+    }
+
+    /// Returns all ODs that haven't been checked since `since`.
+    pub async fn get_unchecked_opendirectories(
+        &self,
+        since: &DateTime,
+    ) -> Result<ModelCursor<OpenDirectory>> {
+        let filter = doc! {"$or": [
+            {"last_checked": null},
+            {"last_checked": {"$lt": since.0}}
+        ]};
+        Ok(OpenDirectory::find(&self.db, filter, None).await?)
+    }
+
     pub async fn get_links(&self, opendirectory: &str) -> Result<ModelCursor<Link>> {
         Ok(Link::find(&self.db, doc! {"opendirectory": opendirectory}, None).await?)
     }
 
+    /// Returns the unfinished export with this scope, if there is one.
+    pub async fn unfinished_export_run(
+        &self,
+        opendirectory: Option<&str>,
+        since: Option<&DateTime>,
+    ) -> Result<Option<ExportRun>> {
+        let filter = doc! {
+            "finished": null,
+            "opendirectory": opendirectory.map_or(Bson::Null, Bson::from),
+            "since": since.map_or(Bson::Null, |s| Bson::from(s.0)),
+        };
+        Ok(ExportRun::find_one(&self.db, filter, None).await?)
+    }
+
+    /// URLs of all alive ODs.
+    pub async fn alive_opendirectory_urls(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .get_opendirectories(false)
+            .await?
+            .filter_map(|od| async { od.ok().map(|od| od.url) })
+            .collect()
+            .await)
+    }
+
+    /// Aggregation stages restricting the links matching `filter` to searchable ones, i.e. of
+    /// alive ODs and not missing. The OD of each link is looked up, so the query stays the same
+    /// size however many ODs there are.
+    fn searchable_pipeline(&self, filter: Document) -> Vec<Document> {
+        vec![
+            doc! {"$match": {"$and": [filter, {
+                "missing": {"$not": {"$gte": crate::MISSING_LINK_THRESHOLD}}
+            }]}},
+            doc! {"$lookup": {
+                "from": OpenDirectory::COLLECTION_NAME,
+                "localField": "opendirectory",
+                "foreignField": "url",
+                "as": "od"
+            }},
+            doc! {"$unwind": "$od"},
+            doc! {"$match": self.alive_filter_at("od.")},
+        ]
+    }
+
+    /// Returns the searchable links matching `filter`, sorted by ID.
+    pub async fn get_searchable_links(
+        &self,
+        filter: Document,
+    ) -> Result<BoxStream<'static, Result<Link>>> {
+        let mut pipeline = vec![doc! {"$sort": {"_id": 1}}];
+        pipeline.extend(self.searchable_pipeline(filter));
+        pipeline.push(doc! {"$project": {"od": 0}});
+        // Links of a single OD are sorted in memory, which may exceed the limit for large ODs
+        let options = options::AggregateOptions::builder()
+            .allow_disk_use(true)
+            .build();
+        let cursor = Link::collection(&self.db)
+            .aggregate(pipeline, options)
+            .await?;
+        Ok(cursor
+            .map(|doc| Ok(Link::instance_from_document(doc?)?))
+            .boxed())
+    }
+
+    /// Returns the links with these IDs, skipping IDs which don't exist or are invalid.
+    pub async fn get_links_by_ids(&self, ids: &[String]) -> Result<Vec<Link>> {
+        let ids: Vec<ObjectId> = ids
+            .iter()
+            .filter_map(|id| ObjectId::with_string(id).ok())
+            .collect();
+        Ok(Link::find(&self.db, doc! {"_id": {"$in": ids}}, None)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    pub async fn count_searchable_links(&self, filter: Document) -> Result<u64> {
+        let mut pipeline = self.searchable_pipeline(filter);
+        pipeline.push(doc! {"$count": "count"});
+        let mut cursor = Link::collection(&self.db).aggregate(pipeline, None).await?;
+        // Nothing is returned if no links match
+        let doc = match cursor.next().await {
+            Some(doc) => doc?,
+            None => return Ok(0),
+        };
+        match doc.get("count") {
+            Some(Bson::Int32(count)) => Ok(*count as u64),
+            Some(Bson::Int64(count)) => Ok(*count as u64),
+            other => bail!("Invalid count of searchable links: {:?}", other),
+        }
+    }
+
+    /// Returns up to `limit` links which haven't been classified yet.
+    pub async fn get_unclassified_links(&self, limit: i64) -> Result<ModelCursor<Link>> {
+        let options = options::FindOptions::builder().limit(limit).build();
+        Ok(Link::find(&self.db, doc! {"category": {"$exists": false}}, options).await?)
+    }
+
+    /// Sets the category of links, `None` marking them as unclassifiable.
+    pub async fn set_category(&self, ids: Vec<ObjectId>, category: Option<Category>) -> Result<()> {
+        let category = category.map_or(Bson::Null, |c| Bson::from(c.as_str()));
+        Link::collection(&self.db)
+            .update_many(
+                doc! {"_id": {"$in": ids}},
+                doc! {"$set": {"category": category}},
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Returns up to `size` random links of an OD.
+    pub async fn sample_links(&self, opendirectory: &str, size: i64) -> Result<Vec<Link>> {
+        let pipeline = vec![
+            doc! {"$match": {"opendirectory": opendirectory}},
+            doc! {"$sample": {"size": size}},
+        ];
+        let mut cursor = Link::collection(&self.db).aggregate(pipeline, None).await?;
+        let mut links = vec![];
+        while let Some(doc) = cursor.next().await {
+            links.push(Link::instance_from_document(doc?)?);
+        }
+        Ok(links)
+    }
+
+    /// Saves the certificate of a host, keeping track of when it changed.
+    pub async fn save_certificate(&self, mut certificate: Certificate) -> Result<Certificate> {
+        if let Some(existing) =
+            Certificate::find_one(&self.db, doc! {"host": &certificate.host}, None).await?
+        {
+            certificate.id = existing.id;
+            if existing.fingerprint != certificate.fingerprint {
+                certificate.previous_fingerprint = Some(existing.fingerprint);
+                certificate.changed = Some(certificate.checked);
+            } else {
+                certificate.previous_fingerprint = existing.previous_fingerprint;
+                certificate.changed = existing.changed;
+            }
+        }
+        certificate.save(&self.db, None).await?;
+        Ok(certificate)
+    }
+
+    pub async fn get_certificate(&self, host: &str) -> Result<Option<Certificate>> {
+        Ok(Certificate::find_one(&self.db, doc! {"host": host}, None).await?)
+    }
+
+    pub async fn get_certificates(&self) -> Result<ModelCursor<Certificate>> {
+        Ok(Certificate::find(&self.db, None, None).await?)
+    }
+
+    /// Moves all links of an OD to a new root URL, keeping their IDs.
+    pub async fn move_links(&self, old_root: &str, new_root: &str) -> Result<()> {
+        // Only one of the roots may end with a slash, e.g. after a redirect to /files/
+        let with_slash = |root: &str| format!("{}/", root.trim_end_matches('/'));
+        let (old_prefix, new_prefix) = (with_slash(old_root), with_slash(new_root));
+        let old_len = old_prefix.chars().count() as i32;
+        let pipeline = vec![doc! {"$set": {
+            "opendirectory": new_root,
+            "url": {"$cond": [
+                {"$eq": [{"$indexOfCP": ["$url", &old_prefix]}, 0]},
+                {"$concat": [&new_prefix, {"$substrCP": ["$url", old_len, i32::MAX]}]},
+                "$url"
+            ]}
+        }}];
+        Link::collection(&self.db)
+            .update_many(
+                doc! {"opendirectory": old_root},
+                options::UpdateModifications::Pipeline(pipeline),
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
     pub async fn stats(&self) -> Result<Stats> {
         let total_links = Link::collection(&self.db)
             .estimated_document_count(None)
@@ -136,19 +596,51 @@ impl Database {
             .estimated_document_count(None)
             .await?;
         let alive_opendirectories = OpenDirectory::collection(&self.db)
-            .count_documents(
-                doc! {"unreachable": doc! {"$lt": crate::DEAD_OD_THRESHOLD}},
-                None,
-            )
+            .count_documents(self.alive_filter(), None)
             .await?;
+        let categories = self.category_counts().await?;
 
         Ok(Stats {
             alive_opendirectories,
             total_links,
             total_opendirectories,
+            categories,
         })
     }
 
+    /// Counts the links in each category in a single pass, reusing the last counts for
+    /// [CATEGORY_COUNTS_TTL].
+    async fn category_counts(&self) -> Result<CategoryCounts> {
+        if let Some((counted, counts)) = &*self.category_counts.lock().unwrap() {
+            if counted.elapsed() < CATEGORY_COUNTS_TTL {
+                return Ok(counts.clone());
+            }
+        }
+
+        let names: Vec<&str> = Category::ALL.iter().map(|c| c.as_str()).collect();
+        let pipeline = vec![
+            doc! {"$match": {"category": {"$in": names}}},
+            doc! {"$group": {"_id": "$category", "count": {"$sum": 1}}},
+        ];
+        let mut cursor = Link::collection(&self.db).aggregate(pipeline, None).await?;
+        let mut counts: CategoryCounts = Category::ALL.iter().map(|c| (*c, 0)).collect();
+        while let Some(doc) = cursor.next().await {
+            let doc = doc?;
+            let name = doc.get_str("_id")?;
+            let count = match doc.get("count") {
+                Some(Bson::Int32(count)) => *count as i64,
+                Some(Bson::Int64(count)) => *count,
+                other => bail!("Invalid count for category {}: {:?}", name, other),
+            };
+            if let Some(category) = Category::ALL.iter().find(|c| c.as_str() == name) {
+                counts.insert(*category, count);
+            }
+        }
+
+        *self.category_counts.lock().unwrap() = Some((Instant::now(), counts.clone()));
+        Ok(counts)
+    }
+
     pub async fn save_scan_result(
         &mut self,
         root_url: &str,
@@ -160,7 +652,16 @@ impl Database {
         let mut od = OpenDirectory {
             id: None,
             url: root_url.to_string(),
-            unreachable: if is_reachable { 0 } else { 10 },
+            unreachable: if is_reachable {
+                0
+            } else {
+                self.dead_od_threshold
+            },
+            last_checked: Some(chrono::Utc::now().into()),
+            file_success_rate: None,
+            final_url: None,
+            pinned: None,
+            dead_threshold: None,
         };
         if let Some(existing) =
             OpenDirectory::find_one(&self.db, doc! {"url": &od.url}, None).await?
@@ -172,12 +673,11 @@ impl Database {
             return Ok(SaveResult::DuplicateOd);
         }
 
-        let links: Vec<Document> = files
-            .drain(..)
-            .map(|l| l.document_from_instance().unwrap())
-            .collect();
-        for link in &links {
-            if let Some(existing) = Link::find_one(&self.db, link.clone(), None).await? {
+        // URLs are unique, whatever else differs. Batched to stay below the maximum query size.
+        for batch in files.chunks(1_000) {
+            let urls: Vec<&str> = batch.iter().map(|l| l.url.as_str()).collect();
+            let filter = doc! {"url": {"$in": urls}};
+            if let Some(existing) = Link::find_one(&self.db, filter, None).await? {
                 error!(
                     "Found existing link, aborting: {}",
                     existing.document_from_instance()?
@@ -185,6 +685,10 @@ impl Database {
                 return Ok(SaveResult::DuplicateLinks);
             }
         }
+        let links: Vec<Document> = files
+            .drain(..)
+            .map(|l| l.document_from_instance().unwrap())
+            .collect();
 
         od.save(&self.db, None).await?;
         Link::collection(&self.db).insert_many(links, None).await?;
@@ -0,0 +1,158 @@
+use anyhow::{bail, Result};
+use futures::AsyncReadExt;
+use isahc::auth::{Authentication, Credentials};
+use isahc::config::CaCertificate;
+use isahc::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use isahc::http::{Method, Request, Response, StatusCode};
+use isahc::prelude::Configurable;
+use isahc::{Body, HttpClient};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use structopt::StructOpt;
+
+/// Where and how to connect to Elasticsearch. The crawler and the web interface take the same
+/// options, which can also be given as environment variables.
+#[derive(StructOpt, Clone)]
+pub struct Config {
+    /// Elasticsearch node addresses, comma-separated. Others are tried if one can't be reached.
+    #[structopt(
+        long = "elastic-url",
+        env = "ELASTIC_URL",
+        default_value = "http://127.0.0.1:9200",
+        use_delimiter = true
+    )]
+    pub urls: Vec<String>,
+
+    /// Elasticsearch alias for links, also used as prefix of its indices
+    #[structopt(long = "elastic-index", env = "ELASTIC_INDEX", default_value = "links")]
+    pub index: String,
+
+    /// Elasticsearch user
+    #[structopt(long = "elastic-user", env = "ELASTIC_USER", default_value = "elastic")]
+    pub user: String,
+
+    /// Elasticsearch password
+    #[structopt(long = "elastic-pass", env = "ELASTIC_PASS", default_value = "")]
+    pub pass: String,
+
+    /// Elasticsearch API key, used instead of user and password
+    #[structopt(long = "elastic-api-key", env = "ELASTIC_API_KEY")]
+    pub api_key: Option<String>,
+
+    /// CA certificate (PEM) to verify Elasticsearch nodes with
+    #[structopt(long = "elastic-ca-cert", env = "ELASTIC_CA_CERT")]
+    pub ca_certificate: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            urls: vec!["http://127.0.0.1:9200".to_string()],
+            index: "links".to_string(),
+            user: "elastic".to_string(),
+            pass: String::new(),
+            api_key: None,
+            ca_certificate: None,
+        }
+    }
+}
+
+/// Leaves out the password and API key, since options are printed on startup.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("urls", &self.urls)
+            .field("index", &self.index)
+            .field("user", &self.user)
+            .field("pass", &"<redacted>")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("ca_certificate", &self.ca_certificate)
+            .finish()
+    }
+}
+
+/// HTTP client for the nodes of a cluster, so connections are reused between requests.
+#[derive(Debug)]
+pub struct Connection {
+    http: HttpClient,
+    urls: Vec<String>,
+    /// Index into `urls` of the node that answered last
+    node: AtomicUsize,
+}
+
+impl Connection {
+    pub fn new(config: &Config) -> Result<Self> {
+        if config.urls.is_empty() {
+            bail!("No Elasticsearch nodes configured");
+        }
+        let mut builder = HttpClient::builder().default_header(CONTENT_TYPE, "application/json");
+        builder = match &config.api_key {
+            Some(key) => builder.default_header(AUTHORIZATION, format!("ApiKey {}", key)),
+            None => builder
+                .authentication(Authentication::basic())
+                .credentials(Credentials::new(config.user.as_str(), config.pass.as_str())),
+        };
+        if let Some(path) = &config.ca_certificate {
+            builder = builder.ssl_ca_certificate(CaCertificate::file(path));
+        }
+        Ok(Self {
+            http: builder.build()?,
+            urls: config
+                .urls
+                .iter()
+                .map(|url| url.trim_end_matches('/').to_string())
+                .collect(),
+            node: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sends a request to the node that answered last, failing over to the others if it can't
+    /// be reached.
+    pub async fn send(&self, method: Method, path: &str, body: String) -> Result<Response<Body>> {
+        let first = self.node.load(Ordering::Relaxed);
+        let mut last_error = None;
+        for offset in 0..self.urls.len() {
+            let node = (first + offset) % self.urls.len();
+            // An empty body would still be sent, and HEAD requests would wait for a response body
+            let body = match body.as_str() {
+                "" => Body::empty(),
+                body => Body::from(body.to_string()),
+            };
+            let request = Request::builder()
+                .method(method.clone())
+                .uri(format!("{}/{}", self.urls[node], path))
+                .body(body)?;
+            match self.http.send_async(request).await {
+                Ok(response) => {
+                    if node != first {
+                        info!("Switched to Elasticsearch node {}", self.urls[node]);
+                        self.node.store(node, Ordering::Relaxed);
+                    }
+                    return Ok(response);
+                }
+                Err(e) => {
+                    warn!("Elasticsearch node {} failed: {}", self.urls[node], e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        match last_error {
+            Some(e) => Err(e.into()),
+            None => bail!("No Elasticsearch nodes configured"),
+        }
+    }
+
+    /// Like [Connection::send], but reads the whole response.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: String,
+    ) -> Result<(StatusCode, String)> {
+        let mut response = self.send(method, path, body).await?;
+        let mut buffer = String::new();
+        response.body_mut().read_to_string(&mut buffer).await?;
+        Ok((response.status(), buffer))
+    }
+}
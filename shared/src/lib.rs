@@ -2,5 +2,21 @@
 extern crate log;
 
 pub mod db;
+pub mod elastic;
+pub mod media;
 
-pub const DEAD_OD_THRESHOLD: i32 = 10;
+/// Used until a different threshold is configured
+pub const DEFAULT_DEAD_OD_THRESHOLD: i32 = 10;
+
+/// Number of failed file samples after which a link is removed from search
+pub const MISSING_LINK_THRESHOLD: i32 = 3;
+
+/// Certificates expiring within this many days are flagged
+pub const CERT_EXPIRY_WARNING_DAYS: i64 = 14;
+/// Certificates which changed within this many days are flagged
+pub const CERT_CHANGE_WARNING_DAYS: i64 = 30;
+
+/// Extracts the host from a URL.
+pub fn host_of(url: &str) -> Option<String> {
+    url::Url::parse(url).ok()?.host_str().map(String::from)
+}
@@ -0,0 +1,138 @@
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// A rough media category, so search can filter by e.g. video instead of every video extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    Video,
+    Audio,
+    Image,
+    Document,
+    Ebook,
+    Archive,
+    Software,
+    DiskImage,
+    Subtitle,
+}
+
+impl Category {
+    pub const ALL: [Category; 9] = [
+        Category::Video,
+        Category::Audio,
+        Category::Image,
+        Category::Document,
+        Category::Ebook,
+        Category::Archive,
+        Category::Software,
+        Category::DiskImage,
+        Category::Subtitle,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Category::Video => "video",
+            Category::Audio => "audio",
+            Category::Image => "image",
+            Category::Document => "document",
+            Category::Ebook => "ebook",
+            Category::Archive => "archive",
+            Category::Software => "software",
+            Category::DiskImage => "disk_image",
+            Category::Subtitle => "subtitle",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        let category = match extension.to_ascii_lowercase().as_str() {
+            "mkv" | "mp4" | "avi" | "m4v" | "mov" | "wmv" | "webm" | "mpg" | "mpeg" | "ts"
+            | "flv" | "vob" | "m2ts" | "ogv" | "3gp" => Category::Video,
+            "mp3" | "flac" | "m4a" | "m4b" | "ogg" | "opus" | "wav" | "aac" | "wma" | "ape"
+            | "aiff" | "mka" => Category::Audio,
+            "jpg" | "jpeg" | "png" | "gif" | "bmp" | "webp" | "tif" | "tiff" | "heic" | "svg" => {
+                Category::Image
+            }
+            "pdf" | "doc" | "docx" | "txt" | "odt" | "rtf" | "xls" | "xlsx" | "ods" | "ppt"
+            | "pptx" | "odp" | "csv" => Category::Document,
+            "epub" | "mobi" | "azw" | "azw3" | "djvu" | "cbz" | "cbr" | "fb2" => Category::Ebook,
+            "zip" | "rar" | "7z" | "tar" | "gz" | "tgz" | "bz2" | "xz" | "zst" => Category::Archive,
+            "exe" | "msi" | "apk" | "dmg" | "deb" | "rpm" | "appimage" | "jar" => {
+                Category::Software
+            }
+            "iso" | "img" | "bin" | "cue" | "nrg" | "mdf" | "vhd" | "vmdk" => Category::DiskImage,
+            "srt" | "sub" | "idx" | "vtt" | "ass" | "ssa" => Category::Subtitle,
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    /// Classifies a Content-Type. Generic types like `application/octet-stream` and HTML pages
+    /// don't tell anything about a file, so they have no category.
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        let mime = mime.split(';').next()?.trim().to_ascii_lowercase();
+        let category = match mime.as_str() {
+            "image/vnd.djvu"
+            | "application/epub+zip"
+            | "application/x-mobipocket-ebook"
+            | "application/vnd.comicbook+zip"
+            | "application/vnd.comicbook-rar" => Category::Ebook,
+            "text/vtt" | "application/x-subrip" | "text/x-ssa" => Category::Subtitle,
+            "application/pdf"
+            | "application/msword"
+            | "application/rtf"
+            | "text/plain"
+            | "text/csv"
+            | "application/vnd.ms-excel"
+            | "application/vnd.ms-powerpoint" => Category::Document,
+            "application/zip"
+            | "application/x-rar-compressed"
+            | "application/vnd.rar"
+            | "application/x-7z-compressed"
+            | "application/x-tar"
+            | "application/gzip"
+            | "application/x-gzip"
+            | "application/x-bzip2"
+            | "application/x-xz"
+            | "application/zstd" => Category::Archive,
+            "application/x-msdownload"
+            | "application/x-msi"
+            | "application/x-ms-installer"
+            | "application/vnd.android.package-archive"
+            | "application/x-apple-diskimage"
+            | "application/vnd.debian.binary-package"
+            | "application/x-rpm"
+            | "application/java-archive" => Category::Software,
+            "application/x-iso9660-image" | "application/x-cd-image" => Category::DiskImage,
+            mime if mime.starts_with("video/") => Category::Video,
+            mime if mime.starts_with("audio/") => Category::Audio,
+            mime if mime.starts_with("image/") => Category::Image,
+            mime if mime.starts_with("application/vnd.openxmlformats-officedocument.")
+                || mime.starts_with("application/vnd.oasis.opendocument.") =>
+            {
+                Category::Document
+            }
+            _ => return None,
+        };
+        Some(category)
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Category {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Category::ALL
+            .iter()
+            .find(|c| c.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown category '{}'", s))
+    }
+}
@@ -1,134 +1,890 @@
-use crate::{elastic, Opt};
-use anyhow::Result;
-use futures::StreamExt;
+use crate::elastic::ElasticLink;
+use crate::ftp;
+use crate::politeness::{interleave_by_host, HostLimiter};
+use crate::proxy::Failure;
+use crate::soft404::{self, PageKind};
+use crate::tls;
+use crate::{search, Opt};
+use anyhow::{bail, Result};
+use async_std::channel::Sender;
+use futures::{AsyncReadExt, FutureExt, StreamExt};
 use isahc::config::SslOption;
-use isahc::prelude::{Configurable, Request, RequestExt};
+use isahc::http::header::{CONTENT_TYPE, LOCATION, RANGE, USER_AGENT};
+use isahc::http::StatusCode;
+use isahc::prelude::{Configurable, Request, RequestExt, Response};
+use isahc::Body;
 use shared::db::Database;
-use shared::db::OpenDirectory;
-use shared::DEAD_OD_THRESHOLD;
+use shared::db::{Certificate, Link, OpenDirectory};
+use shared::media::Category;
+use std::collections::HashSet;
+use std::fmt::{self, Write};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::Duration;
+use url::Url;
 use wither::Model;
 
-pub async fn check_opendirectories(opt: &Opt, db: &mut Database) -> Result<()> {
-    let ods: Mutex<Vec<(OpenDirectory, bool)>> = Mutex::new(vec![]);
-
-    info!("Checking ODs concurrently");
+/// How many check results may be waiting to be persisted
+const RESULT_BUFFER: usize = 256;
+/// How many check results are persisted at once
+const PERSIST_CONCURRENCY: usize = 8;
 
-    let total = OpenDirectory::collection(&db.db)
-        .estimated_document_count(None)
-        .await?;
-    let count = AtomicUsize::new(0);
+pub async fn check_opendirectories(opt: &Opt, db: &mut Database) -> Result<()> {
+    let mut run = db.current_check_run().await?;
+    info!("Checking ODs concurrently (run started {})", run.started.0);
 
-    db.get_opendirectories(true)
+    let to_check: Vec<OpenDirectory> = db
+        .get_unchecked_opendirectories(&run.started)
         .await?
         .filter_map(|res| async { res.ok() })
-        .for_each_concurrent(128, |od| async {
-            let reachable = link_is_reachable(&od.url, Duration::from_secs(20), false).await;
-            match ods.lock() {
-                Ok(mut ods) => {
-                    ods.push((od, reachable));
-                }
-                Err(_) => {
-                    error!("Poisoned Mutex, something went wrong in a different closure");
-                }
-            }
+        .collect()
+        .await;
+    let total = to_check.len();
+    let count = AtomicUsize::new(0);
+    let limiter = HostLimiter::new(opt);
+    let timeout = Duration::from_secs(opt.check_timeout);
+    let certificate_hosts = Mutex::new(HashSet::new());
+    let (sender, receiver) = async_std::channel::bounded(RESULT_BUFFER);
 
-            let current = count.fetch_add(1, Ordering::Relaxed) + 1;
-            if current % 100 == 0 {
-                info!("Checked {}/{} links", current, total);
-            }
+    let check = async {
+        futures::stream::iter(interleave_by_host(to_check, |od| &od.url))
+            .for_each_concurrent(opt.check_concurrency, |od| {
+                let hosts = &certificate_hosts;
+                check_opendirectory(opt, db, &limiter, hosts, &sender, od, timeout)
+            })
+            .await;
+        // Closes the channel, so persisting finishes once the buffer is drained
+        drop(sender);
+    };
+
+    let persist = receiver
+        .for_each_concurrent(PERSIST_CONCURRENCY, |checked| {
+            persist_result(opt, db, &count, total, checked)
         })
-        .await;
+        // Boxed to work around https://github.com/rust-lang/rust/issues/64552
+        .boxed();
+
+    futures::join!(check, persist);
+
+    run.finished = Some(chrono::Utc::now().into());
+    run.save(&db.db, None).await?;
+    info!("Finished checking {} ODs", count.load(Ordering::Relaxed));
+
+    Ok(())
+}
+
+struct CheckedOpenDirectory {
+    od: OpenDirectory,
+    outcome: CheckOutcome,
+    samples: Vec<Sample>,
+    certificate: Option<Certificate>,
+}
+
+/// A randomly picked file of an OD, checked alongside its root.
+struct Sample {
+    link: Link,
+    reachable: bool,
+    /// What the server claimed the file is
+    content_type: Option<String>,
+}
+
+async fn check_opendirectory(
+    opt: &Opt,
+    db: &Database,
+    limiter: &HostLimiter<'_>,
+    certificate_hosts: &Mutex<HashSet<String>>,
+    sender: &Sender<CheckedOpenDirectory>,
+    od: OpenDirectory,
+    timeout: Duration,
+) {
+    // Every request, including redirect hops, is limited by the host it goes to
+    let outcome = check_link(opt, &od.url, timeout, false, Some(limiter)).await;
+    let samples = if outcome.reachable {
+        sample_files(opt, db, &od, timeout, Some(limiter)).await
+    } else {
+        vec![]
+    };
+    // Only fetch each host's certificate once per run
+    let new_host = match shared::host_of(&od.url) {
+        Some(host) => certificate_hosts.lock().unwrap().insert(host),
+        None => false,
+    };
+    let certificate = if new_host {
+        let _permit = limiter.acquire(&od.url).await;
+        fetch_certificate(opt, &od.url, timeout).await
+    } else {
+        None
+    };
+
+    let checked = CheckedOpenDirectory {
+        od,
+        outcome,
+        samples,
+        certificate,
+    };
+    if sender.send(checked).await.is_err() {
+        error!("Result channel closed, something went wrong while persisting");
+    }
+}
+
+async fn fetch_certificate(opt: &Opt, link: &str, timeout: Duration) -> Option<Certificate> {
+    if !link.to_ascii_lowercase().starts_with("https://") {
+        return None;
+    }
+    let proxy = opt.proxies.for_link(link).map(|(_, uri)| uri);
+    match tls::fetch_certificate(link, proxy, timeout).await {
+        Ok(certificate) => Some(certificate),
+        Err(e) => {
+            debug!("Failed to get certificate for {}: {}", link, e);
+            None
+        }
+    }
+}
+
+/// Checks whether some random files of an OD are reachable, because the root alone can be
+/// reachable while the files behind it are gone.
+/// With a `limiter`, each file waits for a slot of its host.
+async fn sample_files(
+    opt: &Opt,
+    db: &Database,
+    od: &OpenDirectory,
+    timeout: Duration,
+    limiter: Option<&HostLimiter<'_>>,
+) -> Vec<Sample> {
+    let links = match db.sample_links(&od.url, opt.file_samples).await {
+        Ok(links) => links,
+        Err(e) => {
+            error!("Failed to sample links of {}: {}", od.url, e);
+            return vec![];
+        }
+    };
 
-    info!("Persisting results");
-    // There are no other users of this mutex now
-    for (od, reachable) in ods.into_inner().unwrap() {
-        if let Err(e) = persists_checked_opendirectory(&opt, &db, od, reachable).await {
-            error!("Error saving OD to DB: {}", e);
+    let mut samples = vec![];
+    for link in links {
+        let _permit = match limiter {
+            Some(limiter) => Some(limiter.acquire(&link.url).await),
+            None => None,
         };
+        let (reachable, content_type) = check_file(opt, &link.url, timeout).await;
+        samples.push(Sample {
+            link,
+            reachable,
+            content_type,
+        });
     }
+    samples
+}
 
-    Ok(())
+/// Checks whether a single file is reachable, without following redirects or downloading it.
+/// Also returns its Content-Type, if the server sent one.
+async fn check_file(opt: &Opt, link: &str, timeout: Duration) -> (bool, Option<String>) {
+    let link = link.replace(" ", "%20");
+    if ftp::is_ftp(&link) {
+        return (ftp::check(opt, &link, timeout).await.is_ok(), None);
+    }
+    match send_request(opt, &link, timeout, false).await {
+        Ok(r) => {
+            let content_type = r
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from);
+            (
+                r.status().is_success() || r.status().is_redirection(),
+                content_type,
+            )
+        }
+        Err(_) => (false, None),
+    }
 }
 
-pub async fn persists_checked_opendirectory(
+async fn persist_result(
     opt: &Opt,
     db: &Database,
-    mut od: OpenDirectory,
-    reachable: bool,
-) -> Result<()> {
-    if reachable {
-        // Re-add links if it was dead
-        if od.unreachable >= DEAD_OD_THRESHOLD {
-            elastic::add_links_from_db(opt, db, &od.url).await?;
+    count: &AtomicUsize,
+    total: usize,
+    checked: CheckedOpenDirectory,
+) {
+    if let Err(e) = persist_checked(opt, db, checked).await {
+        error!("Error saving OD to DB: {}", e);
+    };
+
+    let current = count.fetch_add(1, Ordering::Relaxed) + 1;
+    if current % 100 == 0 {
+        info!("Checked {}/{} links", current, total);
+    }
+}
+
+async fn persist_checked(opt: &Opt, db: &Database, checked: CheckedOpenDirectory) -> Result<()> {
+    let CheckedOpenDirectory {
+        od,
+        outcome,
+        samples,
+        certificate,
+    } = checked;
+
+    if let Some(certificate) = certificate {
+        let certificate = db.save_certificate(certificate).await?;
+        if certificate.changed == Some(certificate.checked) {
+            warn!("Certificate of {} changed", certificate.host);
+        }
+        if certificate.expires_soon() {
+            warn!(
+                "Certificate of {} expires soon ({})",
+                certificate.host, certificate.not_after.0
+            );
+        }
+    }
+
+    // Checks of a run can take hours, during which the OD may have been pinned or given
+    // another threshold
+    let mut od = match db.get_opendirectory(&od.url).await? {
+        Some(od) => od,
+        None => bail!("OD {} was removed while it was checked", od.url),
+    };
+    if !samples.is_empty() {
+        let reachable = samples.iter().filter(|s| s.reachable).count();
+        od.file_success_rate = Some(reachable as f64 / samples.len() as f64);
+        if let Err(e) = persist_file_samples(opt, db, &od, samples).await {
+            error!("Error saving sampled links of {}: {}", od.url, e);
         }
-        // Reset to 0 regardless
-        if od.unreachable > 0 {
-            od.unreachable = 0;
-            od.save(&db.db, None).await?;
+    }
+    persists_checked_opendirectory(opt, db, od, &outcome).await
+}
+
+/// Checks a single OD and describes the outcome in detail.
+/// With `persist`, the result is saved just like a scheduled check would.
+pub async fn check_single(opt: &Opt, db: &Database, url: &str, persist: bool) -> Result<String> {
+    let timeout = Duration::from_secs(opt.check_timeout);
+    let outcome = check_link(opt, url, timeout, true, None).await;
+    let mut report = outcome.to_string();
+
+    let od = match db.get_opendirectory(url).await? {
+        Some(od) => od,
+        None if persist => bail!("No OD with URL {}, can't persist the result", url),
+        None => {
+            writeln!(report, "Not in the database")?;
+            return Ok(report);
         }
+    };
+    writeln!(
+        report,
+        "Before: unreachable {}/{}, pinned {:?}, dead {}",
+        od.unreachable,
+        od.threshold(db.dead_od_threshold),
+        od.pinned,
+        od.is_dead(db.dead_od_threshold)
+    )?;
+
+    let samples = if outcome.reachable {
+        sample_files(opt, db, &od, timeout, None).await
     } else {
-        // Remove links only if it was alive
-        if od.unreachable + 1 == DEAD_OD_THRESHOLD {
-            remove_od_links(opt, db, &od).await?;
+        vec![]
+    };
+    let certificate = fetch_certificate(opt, &od.url, timeout).await;
+    if let Some(certificate) = &certificate {
+        writeln!(
+            report,
+            "Certificate: {} issued by {}, valid {}, expires {}",
+            certificate.subject, certificate.issuer, certificate.valid, certificate.not_after.0
+        )?;
+    }
+    for sample in &samples {
+        writeln!(
+            report,
+            "Sampled file reachable: {} {}",
+            sample.reachable, sample.link.url
+        )?;
+    }
+
+    if persist {
+        let url = outcome.moved_to.clone().unwrap_or_else(|| od.url.clone());
+        let checked = CheckedOpenDirectory {
+            od,
+            outcome,
+            samples,
+            certificate,
+        };
+        persist_checked(opt, db, checked).await?;
+        if let Some(od) = db.get_opendirectory(&url).await? {
+            writeln!(
+                report,
+                "After: unreachable {}/{}, dead {}",
+                od.unreachable,
+                od.threshold(db.dead_od_threshold),
+                od.is_dead(db.dead_od_threshold)
+            )?;
+        }
+    }
+    Ok(report)
+}
+
+/// Updates the missing counters of sampled links, and removes links from Elasticsearch
+/// once they have been missing too often (or re-adds them once they're back).
+/// Links without a category are classified by their Content-Type.
+async fn persist_file_samples(
+    opt: &Opt,
+    db: &Database,
+    od: &OpenDirectory,
+    samples: Vec<Sample>,
+) -> Result<()> {
+    let od_alive = !od.is_dead(db.dead_od_threshold);
+    let mut to_add = vec![];
+    let mut to_remove = vec![];
+    for sample in samples {
+        let mut link = sample.link;
+        let was_missing = link.is_missing();
+        if sample.reachable {
+            link.missing = 0;
+        } else if !was_missing {
+            link.missing += 1;
         }
+        let sniffed = sample.content_type.as_deref().and_then(Category::from_mime);
+        let classified = link.category.is_none() && sniffed.is_some();
+        if classified {
+            link.category = sniffed;
+        }
+        db.save_sample_result(&link).await?;
+
+        if !link.is_missing() && (was_missing || classified) {
+            match ElasticLink::new(link, od_alive) {
+                Ok(document) => to_add.push(document),
+                Err(e) => warn!("Skipping link: {}", e),
+            }
+        } else if !was_missing && link.is_missing() {
+            info!("{} is missing, removing it from search", link.url);
+            to_remove.extend(link.id.map(|id| id.to_string()));
+        }
+    }
+
+    // Links of dead ODs aren't in Elasticsearch either way
+    if od_alive {
+        if !to_add.is_empty() {
+            opt.search.index(&to_add).await?;
+        }
+        if !to_remove.is_empty() {
+            opt.search.delete(&to_remove).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn persists_checked_opendirectory(
+    opt: &Opt,
+    db: &Database,
+    mut od: OpenDirectory,
+    outcome: &CheckOutcome,
+) -> Result<()> {
+    let was_dead = od.is_dead(db.dead_od_threshold);
+    if outcome.reachable {
+        od.unreachable = 0;
+    } else if od.unreachable < od.threshold(db.dead_od_threshold) {
         // Increment if it's below the threshold
-        if od.unreachable < DEAD_OD_THRESHOLD {
-            od.unreachable = od.unreachable.saturating_add(1);
-            od.save(&db.db, None).await?;
+        od.unreachable = od.unreachable.saturating_add(1);
+    }
+    update_search_index(opt, db, &od, was_dead).await?;
+    od.last_checked = Some(chrono::Utc::now().into());
+    od.final_url = outcome.final_url.clone();
+    db.save_check_result(&od).await?;
+
+    if let (true, Some(new_url)) = (outcome.reachable, &outcome.moved_to) {
+        move_opendirectory(opt, db, od, new_url).await?;
+    }
+    Ok(())
+}
+
+/// Rewrites the URL of an OD and all its links after it permanently moved.
+async fn move_opendirectory(
+    opt: &Opt,
+    db: &Database,
+    mut od: OpenDirectory,
+    new_url: &str,
+) -> Result<()> {
+    if db.get_opendirectory(new_url).await?.is_some() {
+        warn!(
+            "OD {} moved to {}, but that OD already exists",
+            od.url, new_url
+        );
+        return Ok(());
+    }
+    info!("OD {} permanently moved to {}", od.url, new_url);
+
+    let old_url = std::mem::replace(&mut od.url, new_url.to_string());
+    db.move_links(&old_url, new_url).await?;
+    od.final_url = None;
+    od.save(&db.db, None).await?;
+
+    // Links keep their IDs, so re-adding them overwrites the old URLs
+    if !od.is_dead(db.dead_od_threshold) {
+        search::add_links_from_db(opt, db, new_url).await?;
+    }
+    Ok(())
+}
+
+/// Adds or removes the links of an OD to/from the search index, if it came back to life or died.
+async fn update_search_index(
+    opt: &Opt,
+    db: &Database,
+    od: &OpenDirectory,
+    was_dead: bool,
+) -> Result<()> {
+    let is_dead = od.is_dead(db.dead_od_threshold);
+    if was_dead && !is_dead {
+        search::add_links_from_db(opt, db, &od.url).await?;
+    } else if !was_dead && is_dead {
+        opt.search.delete_opendirectory(&od.url).await?;
+    }
+    Ok(())
+}
+
+/// Adds or removes links of all ODs whose state changed because the dead OD threshold changed.
+pub async fn reevaluate_opendirectories(
+    opt: &Opt,
+    db: &Database,
+    previous_threshold: i32,
+) -> Result<()> {
+    info!(
+        "Dead OD threshold changed from {} to {}, re-evaluating ODs",
+        previous_threshold, db.dead_od_threshold
+    );
+    let mut ods = db.get_opendirectories(true).await?;
+    while let Some(od) = ods.next().await {
+        let od = od?;
+        let was_dead = od.is_dead(previous_threshold);
+        if let Err(e) = update_search_index(opt, db, &od, was_dead).await {
+            error!("Failed to re-evaluate OD {}: {}", od.url, e);
         }
     }
     Ok(())
 }
 
-async fn remove_od_links(opt: &Opt, db: &Database, od: &OpenDirectory) -> Result<()> {
-    info!("Removing links for OD {} from Elasticsearch", od.url);
-    db.get_links(&od.url)
-        .await?
-        .filter_map(|l| async { Some(l.ok()?.id?.to_string()) })
-        .chunks(5_000)
-        .for_each(|chunk| async move {
-            let chunk = chunk;
-            if let Err(e) = elastic::remove_bulk(opt, &chunk) {
-                warn!("Failed to remove chunk from Elasticsearch: {}", e)
-            }
-        })
-        .await;
+/// Pins an OD as alive (`Some(true)`) or dead (`Some(false)`), or removes the pin.
+pub async fn pin_opendirectory(
+    opt: &Opt,
+    db: &Database,
+    url: &str,
+    pinned: Option<bool>,
+) -> Result<()> {
+    update_opendirectory(opt, db, url, |od| od.pinned = pinned).await
+}
+
+/// Sets the dead OD threshold of a single OD, or resets it to the global one.
+pub async fn set_opendirectory_threshold(
+    opt: &Opt,
+    db: &Database,
+    url: &str,
+    threshold: Option<i32>,
+) -> Result<()> {
+    if let Some(t) = threshold.filter(|&t| t <= 0) {
+        bail!("The dead OD threshold must be positive, got {}", t);
+    }
+    update_opendirectory(opt, db, url, |od| od.dead_threshold = threshold).await
+}
+
+async fn update_opendirectory(
+    opt: &Opt,
+    db: &Database,
+    url: &str,
+    update: impl FnOnce(&mut OpenDirectory),
+) -> Result<()> {
+    let mut od = match db.get_opendirectory(url).await? {
+        Some(od) => od,
+        None => bail!("No OD with URL {}", url),
+    };
+    let was_dead = od.is_dead(db.dead_od_threshold);
+    update(&mut od);
+    update_search_index(opt, db, &od, was_dead).await?;
+    od.save(&db.db, None).await?;
     Ok(())
 }
 
-pub async fn link_is_reachable(link: &str, timeout: Duration, log_status: bool) -> bool {
+/// The result of checking a single link.
+#[derive(Debug, Default)]
+pub struct CheckOutcome {
+    pub reachable: bool,
+    /// Status of the last response, if there was one
+    pub status: Option<StatusCode>,
+    /// Where the link redirected to, if it did
+    pub final_url: Option<String>,
+    /// Set if the link permanently moved to an equivalent URL
+    pub moved_to: Option<String>,
+    /// Set if the page was recognized as a soft 404
+    pub page_kind: Option<PageKind>,
+    /// Status and URL of every response, including redirects
+    pub responses: Vec<(StatusCode, String)>,
+    /// Why the link counts as unreachable
+    pub problem: Option<String>,
+}
+
+impl fmt::Display for CheckOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Reachable: {}", self.reachable)?;
+        for (status, url) in &self.responses {
+            writeln!(f, "  {} {}", status, url)?;
+        }
+        if let Some(final_url) = &self.final_url {
+            writeln!(f, "Final URL: {}", final_url)?;
+        }
+        if let Some(moved_to) = &self.moved_to {
+            writeln!(f, "Permanently moved to: {}", moved_to)?;
+        }
+        if let Some(kind) = &self.page_kind {
+            writeln!(f, "Looks like a {}", kind)?;
+        }
+        if let Some(problem) = &self.problem {
+            writeln!(f, "Problem: {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn link_is_reachable(opt: &Opt, link: &str, timeout: Duration, log_status: bool) -> bool {
+    check_link(opt, link, timeout, log_status, None)
+        .await
+        .reachable
+}
+
+/// Checks whether `link` is reachable, following up to `--max-redirects` redirects.
+/// With a `limiter`, each request waits for a slot of the host it goes to.
+pub async fn check_link(
+    opt: &Opt,
+    link: &str,
+    timeout: Duration,
+    log_status: bool,
+    limiter: Option<&HostLimiter<'_>>,
+) -> CheckOutcome {
     // Patch for some non-comformant URLs
     let link = link.replace(" ", "%20");
+    let mut outcome = CheckOutcome::default();
+
+    if ftp::is_ftp(&link) {
+        let _permit = match limiter {
+            Some(limiter) => Some(limiter.acquire(&link).await),
+            None => None,
+        };
+        match ftp::check(opt, &link, timeout).await {
+            Ok(()) => outcome.reachable = true,
+            Err(e) => outcome.problem = Some(format!("FTP error: {}", e)),
+        }
+        if log_status {
+            info!("{} is reachable: {}", link, outcome.reachable);
+        }
+        return outcome;
+    }
+
+    let mut current = link.clone();
+    let mut permanent = true;
+    let mut redirects = 0;
+    // Held until the body of the last response was read
+    let mut permit = None;
+    let mut response = loop {
+        // Release the previous hop first, it may have been to the same host
+        drop(permit.take());
+        if let Some(limiter) = limiter {
+            permit = Some(limiter.acquire(&current).await);
+        }
+        // Only download part of the body if there's something to look for
+        let sniff = !opt.signatures.is_empty();
+        let response = match send_request(opt, &current, timeout, sniff).await {
+            Ok(r) => r,
+            Err(e) => {
+                outcome.problem = Some(format!("Request failed: {}", e));
+                return outcome;
+            }
+        };
+        outcome.status = Some(response.status());
+        outcome.responses.push((response.status(), current.clone()));
+        if log_status {
+            info!("Got {} for {}", response.status(), current);
+        }
+        if !response.status().is_redirection() {
+            break response;
+        }
+
+        let location = match redirect_target(&current, &response) {
+            Some(l) => l,
+            None => {
+                outcome.problem = Some("Redirect without a valid Location".to_string());
+                return outcome;
+            }
+        };
+        redirects += 1;
+        if redirects > opt.max_redirects {
+            info!("Too many redirects for {}", link);
+            outcome.problem = Some("Too many redirects".to_string());
+            return outcome;
+        }
+        permanent &= matches!(
+            response.status(),
+            StatusCode::MOVED_PERMANENTLY | StatusCode::PERMANENT_REDIRECT
+        );
+        current = location;
+    };
+
+    if redirects > 0 {
+        outcome.final_url = Some(current.clone());
+        if is_homepage_redirect(&link, &current) {
+            info!("{} redirects to the homepage {}", link, current);
+            outcome.problem = Some("Redirects to the homepage".to_string());
+            return outcome;
+        }
+        if permanent && is_equivalent_location(&link, &current) {
+            outcome.moved_to = Some(current.clone());
+        }
+    }
 
-    let mut builder = Request::head(&link)
-        .connect_timeout(timeout)
-        .timeout(timeout)
-        .ssl_options(SslOption::DANGER_ACCEPT_INVALID_CERTS);
+    if !response.status().is_success() {
+        outcome.problem = Some(format!("Got status {}", response.status()));
+        return outcome;
+    }
+    if !opt.signatures.is_empty() {
+        if let Some(kind) = classify_page(opt, &mut response).await {
+            info!(
+                "{} looks like a {}, counting it as unreachable",
+                current, kind
+            );
+            outcome.page_kind = Some(kind);
+            outcome.problem = Some(format!("Looks like a {}", kind));
+            return outcome;
+        }
+    }
+    outcome.reachable = true;
+    outcome
+}
 
-    // Workaround for hashhacker's "AI protection"
-    if link.contains("driveindex.ga") {
-        builder = builder
-            .uri(link.replace("driveindex.ga", "hashhackers.com"))
-            .header("Referer", &link);
+/// Sends a HEAD request, or a GET request for the start of the body if `sniff` is set.
+async fn send_request(
+    opt: &Opt,
+    link: &str,
+    timeout: Duration,
+    sniff: bool,
+) -> Result<Response<Body>> {
+    let mut builder = if sniff {
+        Request::get(link).header(RANGE, format!("bytes=0-{}", soft404::SAMPLE_SIZE - 1))
+    } else {
+        Request::head(link)
+    }
+    .connect_timeout(timeout)
+    .timeout(timeout)
+    .ssl_options(SslOption::DANGER_ACCEPT_INVALID_CERTS);
+    if let Some(user_agent) = &opt.user_agent {
+        builder = builder.header(USER_AGENT, user_agent.as_str());
+    }
+    let proxy = opt.proxies.for_link(link);
+    if let Some((_, uri)) = &proxy {
+        builder = builder.proxy(Some(uri.clone()));
     }
 
-    let request = match builder.body(()) {
+    let request = match opt.rules.apply(link, builder).body(()) {
         Ok(r) => r,
         Err(e) => {
-            error!("Error building request for URI '{}': {}", &link, e);
-            return false;
+            error!("Error building request for URI '{}': {}", link, e);
+            return Err(e.into());
         }
     };
 
-    if let Ok(response) = request.send_async().await {
-        if log_status {
-            info!("Got {} for {}", response.status(), link);
+    let result = request.send_async().await;
+    if let Some((index, _)) = proxy {
+        let failure = result.as_ref().err().map(Failure::from);
+        opt.proxies.report_result(index, failure).await;
+    }
+    Ok(result?)
+}
+
+/// Resolves the Location header of a redirect relative to the requested URL.
+fn redirect_target(link: &str, response: &Response<Body>) -> Option<String> {
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    Some(Url::parse(link).ok()?.join(location).ok()?.to_string())
+}
+
+/// Whether a link to something below the root was redirected to the root of a site.
+fn is_homepage_redirect(from: &str, to: &str) -> bool {
+    match (Url::parse(from), Url::parse(to)) {
+        (Ok(from), Ok(to)) => from.path() != "/" && to.path() == "/",
+        _ => false,
+    }
+}
+
+/// Whether `to` points to the same path as `from`, e.g. after switching to HTTPS or another domain.
+fn is_equivalent_location(from: &str, to: &str) -> bool {
+    match (Url::parse(from), Url::parse(to)) {
+        (Ok(from), Ok(to)) => {
+            from.path().trim_end_matches('/') == to.path().trim_end_matches('/')
+                && from.query() == to.query()
+        }
+        _ => false,
+    }
+}
+
+/// Reads the start of the response body and checks it against the page signatures.
+async fn classify_page(opt: &Opt, response: &mut Response<Body>) -> Option<PageKind> {
+    let mut sample = vec![];
+    if let Err(e) = response
+        .body_mut()
+        .take(soft404::SAMPLE_SIZE)
+        .read_to_end(&mut sample)
+        .await
+    {
+        debug!("Failed to read response body: {}", e);
+        return None;
+    }
+    opt.signatures.classify(&String::from_utf8_lossy(&sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use shared::db::CheckRun;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use wither::bson::doc;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[async_std::test]
+    async fn redirects_wait_for_the_target_host() {
+        let target = testing::http_server(|_| testing::response("200 OK", ""));
+        let location = format!("http://localhost:{}/files/", target.port());
+        let redirect = location.clone();
+        let od =
+            testing::http_server(move |_| testing::redirect("301 Moved Permanently", &redirect));
+        let mut opt = testing::opt();
+        opt.host_concurrency = 1;
+        let limiter = HostLimiter::new(&opt);
+
+        let busy = limiter.acquire(&location).await;
+        let link = format!("http://127.0.0.1:{}/files/", od.port());
+        let check = check_link(&opt, &link, TIMEOUT, false, Some(&limiter));
+        futures::pin_mut!(check);
+        let waiting = async_std::future::timeout(Duration::from_millis(500), check.as_mut());
+        assert!(waiting.await.is_err());
+
+        drop(busy);
+        let outcome = check.await;
+        assert!(outcome.reachable);
+        assert_eq!(outcome.moved_to, Some(location));
+    }
+
+    #[async_std::test]
+    async fn redirects_to_the_same_host_release_their_slot() {
+        let od = testing::http_server(|request| match request.path.as_str() {
+            "/files" => testing::redirect("301 Moved Permanently", "/files/"),
+            _ => testing::response("200 OK", ""),
+        });
+        let mut opt = testing::opt();
+        opt.host_concurrency = 1;
+        let limiter = HostLimiter::new(&opt);
+
+        let link = format!("http://127.0.0.1:{}/files", od.port());
+        let check = check_link(&opt, &link, TIMEOUT, false, Some(&limiter));
+        let outcome = async_std::future::timeout(TIMEOUT, check).await.unwrap();
+        assert!(outcome.reachable);
+        assert_eq!(outcome.responses.len(), 2);
+    }
+
+    #[async_std::test]
+    #[ignore = "needs MongoDB"]
+    async fn interrupted_runs_resume_with_the_unchecked_ods() {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let paths = requested.clone();
+        let server = testing::http_server(move |request| {
+            paths.lock().unwrap().push(request.path.clone());
+            testing::response("200 OK", "")
+        });
+        let mut db = testing::database().await;
+        let opt = testing::opt();
+        let root = format!("http://127.0.0.1:{}", server.port());
+        let mut checked = testing::save_opendirectory(&db, &format!("{}/checked/", root), 0).await;
+        let unchecked = testing::save_opendirectory(&db, &format!("{}/unchecked/", root), 0).await;
+
+        // The crawler stopped after persisting one OD
+        let run = db.current_check_run().await.unwrap();
+        checked.last_checked = Some(chrono::Utc::now().into());
+        db.save_check_result(&checked).await.unwrap();
+
+        let resumed = db.current_check_run().await.unwrap();
+        assert_eq!(resumed.id, run.id);
+        let to_check: Vec<String> = db
+            .get_unchecked_opendirectories(&resumed.started)
+            .await
+            .unwrap()
+            .map(|od| od.unwrap().url)
+            .collect()
+            .await;
+        assert_eq!(to_check, vec![unchecked.url.clone()]);
+
+        check_opendirectories(&opt, &mut db).await.unwrap();
+        let requested = requested.lock().unwrap().clone();
+        assert!(requested.iter().any(|p| p.starts_with("/unchecked/")));
+        assert!(!requested.iter().any(|p| p.starts_with("/checked/")));
+        let unchecked = db.get_opendirectory(&unchecked.url).await.unwrap().unwrap();
+        assert!(unchecked.last_checked.is_some());
+        let run = CheckRun::find_one(&db.db, doc! {"_id": run.id.clone().unwrap()}, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(run.finished.is_some());
+        assert_ne!(db.current_check_run().await.unwrap().id, run.id);
+
+        db.db.drop(None).await.unwrap();
+    }
+
+    #[async_std::test]
+    #[ignore = "needs MongoDB"]
+    async fn threshold_changes_update_the_search_index_unless_overridden() {
+        let mut db = testing::database().await;
+        db.set_dead_od_threshold(3).await.unwrap();
+        let url = |name: &str| format!("http://{}.test/", name);
+        let mut link_ids = HashMap::new();
+        for (name, unreachable, pinned, dead_threshold) in [
+            ("failing", 2, None, None),
+            ("dead", 4, None, None),
+            ("pinned-alive", 4, Some(true), None),
+            ("pinned-dead", 0, Some(false), None),
+            ("low-threshold", 2, None, Some(1)),
+        ]
+        .iter()
+        {
+            let mut od = testing::save_opendirectory(&db, &url(name), *unreachable).await;
+            od.pinned = *pinned;
+            od.dead_threshold = *dead_threshold;
+            od.save(&db.db, None).await.unwrap();
+            let link = testing::save_link(&db, &od, "file.mkv").await;
+            link_ids.insert(*name, link.id.unwrap().to_string());
         }
-        return response.status().is_success() || response.status().is_redirection();
+        let urls = |names: &[&str]| names.iter().map(|n| url(n)).collect::<HashSet<_>>();
+
+        let recorder = Arc::new(testing::Recorder::default());
+        let mut opt = testing::opt();
+        opt.search = (recorder.clone() as Arc<dyn search::SearchIndex>).into();
+        let previous = db.set_dead_od_threshold(2).await.unwrap();
+        reevaluate_opendirectories(&opt, &db, previous)
+            .await
+            .unwrap();
+        assert!(recorder.indexed.lock().unwrap().is_empty());
+        assert_eq!(
+            *recorder.deleted_opendirectories.lock().unwrap(),
+            vec![url("failing")]
+        );
+        let alive = db.alive_opendirectory_urls().await.unwrap();
+        assert_eq!(alive, urls(&["pinned-alive"]));
+
+        let recorder = Arc::new(testing::Recorder::default());
+        opt.search = (recorder.clone() as Arc<dyn search::SearchIndex>).into();
+        let previous = db.set_dead_od_threshold(5).await.unwrap();
+        reevaluate_opendirectories(&opt, &db, previous)
+            .await
+            .unwrap();
+        let mut indexed = recorder.indexed.lock().unwrap().concat();
+        indexed.sort();
+        let mut expected = vec![link_ids["failing"].clone(), link_ids["dead"].clone()];
+        expected.sort();
+        assert_eq!(indexed, expected);
+        assert!(recorder.deleted_opendirectories.lock().unwrap().is_empty());
+        let alive = db.alive_opendirectory_urls().await.unwrap();
+        assert_eq!(alive, urls(&["failing", "dead", "pinned-alive"]));
+
+        db.db.drop(None).await.unwrap();
     }
-    // let isahc log any issues
-    false
 }
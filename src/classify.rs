@@ -0,0 +1,40 @@
+use crate::elastic::link_extension;
+use anyhow::Result;
+use futures::StreamExt;
+use shared::db::Database;
+use shared::media::Category;
+use std::collections::HashMap;
+use wither::bson::oid::ObjectId;
+
+/// How many links are classified per run
+const BATCH_SIZE: i64 = 10_000;
+
+/// Classifies a link by its extension.
+pub fn classify(link: &str) -> Option<Category> {
+    link_extension(link)
+        .as_deref()
+        .and_then(Category::from_extension)
+}
+
+/// Classifies a batch of links saved before links had categories.
+/// Returns the number of classified links, which is 0 once all of them are.
+pub async fn classify_links(db: &Database) -> Result<usize> {
+    let mut categories: HashMap<Option<Category>, Vec<ObjectId>> = HashMap::new();
+    let mut links = db.get_unclassified_links(BATCH_SIZE).await?;
+    while let Some(link) = links.next().await {
+        let link = link?;
+        if let Some(id) = link.id {
+            categories.entry(classify(&link.url)).or_default().push(id);
+        }
+    }
+
+    let mut classified = 0;
+    for (category, ids) in categories {
+        classified += ids.len();
+        db.set_category(ids, category).await?;
+    }
+    if classified > 0 {
+        info!("Classified {} links", classified);
+    }
+    Ok(classified)
+}
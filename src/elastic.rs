@@ -1,133 +1,1175 @@
-use crate::Opt;
-use anyhow::Result;
-use futures::StreamExt;
-use isahc::auth::{Authentication, Credentials};
-use isahc::http::header::CONTENT_TYPE;
-use isahc::prelude::Configurable;
-use isahc::{RequestExt, ResponseExt};
+use crate::search::SearchIndex;
+use anyhow::{bail, Context, Result};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use isahc::http::{Method, StatusCode};
+use percent_encoding::percent_decode_str;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use shared::db;
 use shared::db::Link;
-use std::io::Read;
-use std::path::PathBuf;
+use shared::elastic::Connection;
+use shared::media::Category;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use structopt::StructOpt;
+use url::Url;
+
+/// How often items rejected with a retryable status are resent
+const MAX_RETRIES: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Version of [template], checked against the one stored in Elasticsearch
+const TEMPLATE_VERSION: i64 = 4;
+/// Bulk requests are split once they'd exceed either of these
+const MAX_BULK_ITEMS: usize = 5_000;
+const MAX_BULK_BYTES: usize = 10 * 1024 * 1024;
+/// How often the progress of long-running tasks is checked
+const TASK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Number of IDs fetched at once when listing all documents
+const ID_PAGE_SIZE: usize = 10_000;
+
+/// Where and how to connect to Elasticsearch, and how to treat its index template.
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct Config {
+    #[structopt(flatten)]
+    pub connection: shared::elastic::Config,
+
+    /// Replace an Elasticsearch index template created by an older version. Existing indices
+    /// are rebuilt by a full export on startup afterwards
+    #[structopt(long = "upgrade-index-template")]
+    pub upgrade_template: bool,
+}
+
+/// Elasticsearch client shared by all tasks.
+#[derive(Debug)]
+pub struct Client {
+    connection: Connection,
+    alias: String,
+    upgrade_template: bool,
+}
+
+impl Client {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            connection: Connection::new(&config.connection)?,
+            alias: config.connection.index.clone(),
+            upgrade_template: config.upgrade_template,
+        })
+    }
+
+    /// Creates the index template for the alias if it doesn't exist yet.
+    ///
+    /// A template with a different version is an error, since indices created from it would have
+    /// mismatched mappings. Only if upgrading was asked for, an older template is replaced.
+    pub async fn ensure_template(&self) -> Result<()> {
+        let path = format!("_index_template/{}", self.alias);
+        let (status, body) = self.request(Method::GET, &path, None).await?;
+        if status == StatusCode::NOT_FOUND {
+            info!("Creating index template {}", self.alias);
+            let template = template(&self.alias);
+            let (status, body) = self.request(Method::PUT, &path, Some(template)).await?;
+            if !status.is_success() {
+                bail!("Failed to create index template: {} {}", status, body);
+            }
+            return Ok(());
+        }
+        if !status.is_success() {
+            bail!("Failed to get index template: {} {}", status, body);
+        }
+
+        let response: serde_json::Value = serde_json::from_str(&body)
+            .with_context(|| format!("Invalid index template response: {}", body))?;
+        let version = response["index_templates"][0]["index_template"]["version"].as_i64();
+        match version {
+            Some(TEMPLATE_VERSION) => Ok(()),
+            Some(version) if version > TEMPLATE_VERSION => bail!(
+                "Index template {} has version {}, which is newer than {}",
+                self.alias,
+                version,
+                TEMPLATE_VERSION
+            ),
+            _ if self.upgrade_template => {
+                // Existing indices keep their mappings until they're replaced by a full export
+                warn!(
+                    "Upgrading index template {} from version {:?} to {}",
+                    self.alias, version, TEMPLATE_VERSION
+                );
+                self.request_ok(Method::PUT, &path, Some(template(&self.alias)))
+                    .await?;
+                Ok(())
+            }
+            _ => bail!(
+                "Index template {} has version {:?}, expected {}. Start with --upgrade-index-template to replace it",
+                self.alias,
+                version,
+                TEMPLATE_VERSION
+            ),
+        }
+    }
+
+    /// Whether an existing index was created from an older version of the template.
+    pub async fn has_outdated_mappings(&self, index: &str) -> Result<bool> {
+        if !self.exists(index).await? {
+            return Ok(false);
+        }
+        let response = self
+            .request_ok(Method::GET, &format!("{}/_mapping", index), None)
+            .await?;
+        let indices = response
+            .as_object()
+            .with_context(|| format!("Invalid mapping response: {}", response))?;
+        Ok(indices
+            .values()
+            .any(|i| i["mappings"]["_meta"]["version"].as_i64() != Some(TEMPLATE_VERSION)))
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<(StatusCode, String)> {
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        self.connection.request(method, path, body).await
+    }
+
+    /// Like [request], but fails on non-success status codes.
+    async fn request_ok(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let (status, response) = self.request(method.clone(), path, body).await?;
+        if !status.is_success() {
+            bail!("{} {} failed: {} {}", method, path, status, response);
+        }
+        serde_json::from_str(&response).with_context(|| format!("Invalid response: {}", response))
+    }
+
+    /// Creates a new versioned index for a full export, which isn't searchable until
+    /// [swap_alias] is called.
+    pub async fn create_versioned_index(&self) -> Result<String> {
+        let index = format!(
+            "{}-{}",
+            self.alias,
+            chrono::Utc::now().format("%Y%m%d%H%M%S")
+        );
+        info!("Creating index {}", index);
+        self.request_ok(Method::PUT, &index, None).await?;
+        Ok(index)
+    }
+
+    pub async fn exists(&self, index: &str) -> Result<bool> {
+        let (status, _) = self.request(Method::HEAD, index, None).await?;
+        Ok(status.is_success())
+    }
+
+    /// Makes all written documents visible and counts them.
+    pub async fn count(&self, index: &str) -> Result<u64> {
+        self.request_ok(Method::POST, &format!("{}/_refresh", index), None)
+            .await?;
+        let response = self
+            .request_ok(Method::GET, &format!("{}/_count", index), None)
+            .await?;
+        response["count"]
+            .as_u64()
+            .with_context(|| format!("Invalid count response: {}", response))
+    }
+
+    /// Indices the alias currently points to.
+    async fn aliased_indices(&self) -> Result<Vec<String>> {
+        let (status, response) = self
+            .request(Method::GET, &format!("_alias/{}", self.alias), None)
+            .await?;
+        if status == StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        if !status.is_success() {
+            bail!(
+                "Failed to get alias {}: {} {}",
+                self.alias,
+                status,
+                response
+            );
+        }
+        let response: HashMap<String, serde_json::Value> = serde_json::from_str(&response)
+            .with_context(|| format!("Invalid alias response: {}", response))?;
+        Ok(response.into_keys().collect())
+    }
+
+    /// Atomically points the alias to `index`.
+    pub async fn swap_alias(&self, index: &str) -> Result<()> {
+        let mut actions = vec![];
+        let previous = self.aliased_indices().await?;
+        if previous.is_empty() {
+            // Before the first versioned export, the alias' name may be taken by a plain index,
+            // which has to go
+            let (status, _) = self.request(Method::HEAD, &self.alias, None).await?;
+            if status.is_success() {
+                actions.push(json!({"remove_index": {"index": self.alias}}));
+            }
+        }
+        for old in &previous {
+            actions.push(json!({"remove": {"index": old, "alias": self.alias}}));
+        }
+        actions.push(json!({"add": {"index": index, "alias": self.alias}}));
+
+        info!(
+            "Pointing alias {} to {} (was {:?})",
+            self.alias, index, previous
+        );
+        self.request_ok(
+            Method::POST,
+            "_aliases",
+            Some(json!({ "actions": actions })),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Deletes all but the `retention` newest versioned indices.
+    /// The index the alias points to is never deleted.
+    pub async fn remove_old_indices(&self, retention: usize) -> Result<()> {
+        let response = self
+            .request_ok(
+                Method::GET,
+                &format!("_cat/indices/{}-*?format=json&h=index", self.alias),
+                None,
+            )
+            .await?;
+        // Other aliases may share the prefix, e.g. links-staging
+        let versioned = Regex::new(&format!(r"^{}-\d{{14}}$", regex::escape(&self.alias)))?;
+        let mut indices: Vec<String> = response
+            .as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|i| i["index"].as_str())
+                    .filter(|i| versioned.is_match(i))
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+        // The timestamp suffix sorts chronologically
+        indices.sort_unstable_by(|a, b| b.cmp(a));
+
+        let live = self.aliased_indices().await?;
+        for index in indices.iter().skip(retention) {
+            if live.contains(index) {
+                continue;
+            }
+            info!("Deleting old index {}", index);
+            self.request_ok(Method::DELETE, index, None).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs a `_delete_by_query` as a task and waits for it, logging its progress.
+    /// Returns the number of deleted documents.
+    pub async fn delete_by_query(&self, index: &str, query: serde_json::Value) -> Result<u64> {
+        let response = self
+            .request_ok(
+                Method::POST,
+                &format!(
+                    "{}/_delete_by_query?conflicts=proceed&wait_for_completion=false",
+                    index
+                ),
+                Some(query),
+            )
+            .await?;
+        let task = response["task"]
+            .as_str()
+            .with_context(|| format!("Invalid delete by query response: {}", response))?
+            .to_string();
+
+        loop {
+            async_std::task::sleep(TASK_POLL_INTERVAL).await;
+            let response = self
+                .request_ok(Method::GET, &format!("_tasks/{}", task), None)
+                .await?;
+            let status = &response["task"]["status"];
+            if response["completed"].as_bool() != Some(true) {
+                info!(
+                    "Deleting from {}: {}/{} documents",
+                    index, status["deleted"], status["total"]
+                );
+                continue;
+            }
+
+            if !response["error"].is_null() {
+                bail!("Delete by query failed: {}", response["error"]);
+            }
+            let failures = response["response"]["failures"]
+                .as_array()
+                .map(Vec::len)
+                .unwrap_or_default();
+            if failures > 0 {
+                bail!(
+                    "Delete by query had {} failures: {}",
+                    failures,
+                    response["response"]["failures"]
+                );
+            }
+            return Ok(response["response"]["deleted"].as_u64().unwrap_or_default());
+        }
+    }
+
+    /// Sends a bulk request to `index`, retrying items that were rejected temporarily.
+    /// Returns the number of items that failed permanently.
+    pub async fn bulk_request(&self, index: &str, mut body: BulkBody) -> Result<usize> {
+        let mut failed = 0;
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 0..=MAX_RETRIES {
+            let retries_left = attempt < MAX_RETRIES;
+            let (status, buffer) = self
+                .connection
+                .request(Method::PUT, &format!("{}/_bulk", index), body.to_body())
+                .await?;
+
+            if !status.is_success() {
+                if retries_left && is_retryable(status.as_u16()) {
+                    warn!(
+                        "Elasticsearch responded with {}, retrying in {:?}",
+                        status, backoff
+                    );
+                    async_std::task::sleep(backoff).await;
+                    backoff *= 2;
+                    continue;
+                }
+                bail!("Got status code {}, response was\n {}", status, buffer);
+            }
+
+            let response: BulkResponse = serde_json::from_str(&buffer)
+                .with_context(|| format!("Invalid bulk response: {}", buffer))?;
+            if !response.errors {
+                return Ok(failed);
+            }
+
+            let mut retry = vec![];
+            for (action, result) in body.items.into_iter().zip(response.items) {
+                let (kind, item) = match result.into_iter().next() {
+                    Some(item) => item,
+                    None => continue,
+                };
+                let error = match item.error {
+                    Some(error) => error,
+                    None => continue,
+                };
+                if retries_left && is_retryable(item.status) {
+                    retry.push(action);
+                } else {
+                    warn!("Failed to {} {}: {}", kind, item.id, error);
+                    failed += 1;
+                }
+            }
+            if retry.is_empty() {
+                break;
+            }
+
+            warn!(
+                "Elasticsearch rejected {} items, retrying in {:?}",
+                retry.len(),
+                backoff
+            );
+            body = BulkBody::default();
+            for item in retry {
+                body.push(item);
+            }
+            async_std::task::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        if failed > 0 {
+            error!("{} items failed permanently in bulk request", failed);
+        }
+        Ok(failed)
+    }
+}
+
+/// The index template applied to the alias' indices.
+/// Bump [TEMPLATE_VERSION] whenever this changes.
+fn template(alias: &str) -> serde_json::Value {
+    json!({
+        // Versioned indices are suffixed with a timestamp, so other aliases sharing the prefix
+        // (e.g. links-staging) don't match
+        "index_patterns": [alias, format!("{}-2*", alias)],
+        "version": TEMPLATE_VERSION,
+        "template": {
+            "settings": {
+                "analysis": {
+                    "tokenizer": {
+                        // Splits on anything but letters and digits (dots, underscores, spaces...),
+                        // between letters and digits, and on camelCase boundaries
+                        "filename": {
+                            "type": "pattern",
+                            "pattern": "([^\\p{L}\\d]+)|(?<=\\D)(?=\\d)|(?<=\\d)(?=\\D)|(?<=[\\p{L}&&[^\\p{Lu}]])(?=\\p{Lu})|(?<=\\p{Lu})(?=\\p{Lu}[\\p{L}&&[^\\p{Lu}]])"
+                        }
+                    },
+                    "analyzer": {
+                        "filename": {
+                            "type": "custom",
+                            "tokenizer": "filename",
+                            "filter": ["lowercase", "asciifolding"]
+                        }
+                    },
+                    "normalizer": {
+                        "lowercase": {
+                            "type": "custom",
+                            "filter": ["lowercase"]
+                        }
+                    }
+                }
+            },
+            "mappings": {
+                // Tells which indices were created from an older template
+                "_meta": {
+                    "version": TEMPLATE_VERSION
+                },
+                "dynamic": "strict",
+                "properties": {
+                    "id": {
+                        "type": "keyword"
+                    },
+                    "url": {
+                        "type": "keyword",
+                        "ignore_above": 8191
+                    },
+                    "filename": {
+                        "type": "text",
+                        "analyzer": "filename"
+                    },
+                    "extension": {
+                        "type": "keyword",
+                        "normalizer": "lowercase"
+                    },
+                    "opendirectory": {
+                        "type": "keyword",
+                        "ignore_above": 8191
+                    },
+                    "host": {
+                        "type": "keyword",
+                        "normalizer": "lowercase"
+                    },
+                    "tld": {
+                        "type": "keyword"
+                    },
+                    "segments": {
+                        "type": "text",
+                        "analyzer": "filename"
+                    },
+                    "parent": {
+                        "type": "text",
+                        "analyzer": "filename"
+                    },
+                    "size": {
+                        "type": "long"
+                    },
+                    "last_modified": {
+                        "type": "date"
+                    },
+                    "category": {
+                        "type": "keyword"
+                    },
+                    "alive": {
+                        "type": "boolean"
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// 429 means Elasticsearch's queues are full, 5xx are usually transient as well
+fn is_retryable(status: u16) -> bool {
+    matches!(status, 429 | 502 | 503 | 504)
+}
+
+#[derive(Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    items: Vec<HashMap<String, BulkItemResult>>,
+}
+
+#[derive(Deserialize)]
+struct BulkItemResult {
+    #[serde(rename = "_id", default)]
+    id: String,
+    status: u16,
+    error: Option<serde_json::Value>,
+}
+
+/// [SearchIndex] writing to the alias, or to a new versioned index while rebuilding.
+#[derive(Debug)]
+pub struct Elastic {
+    client: Arc<Client>,
+    index: String,
+    /// Number of versioned indices kept when publishing
+    retention: usize,
+    rebuilding: bool,
+}
+
+impl Elastic {
+    pub async fn open(config: &Config, retention: usize) -> Result<Self> {
+        let client = Client::new(config)?;
+        client.ensure_template().await?;
+        Ok(Self {
+            index: client.alias.clone(),
+            client: Arc::new(client),
+            retention,
+            rebuilding: false,
+        })
+    }
+}
+
+impl Elastic {
+    /// Sends actions in as many bulk requests as needed to stay within the size limits.
+    /// Returns the number of actions that failed permanently.
+    async fn bulk<'a>(&self, actions: impl Iterator<Item = BulkAction<'a>>) -> Result<usize> {
+        let mut failed = 0;
+        let mut body = BulkBody::default();
+        for action in actions {
+            let item = action.serialize();
+            if !body.fits(&item) {
+                let full = std::mem::take(&mut body);
+                failed += self.client.bulk_request(&self.index, full).await?;
+            }
+            body.push(item);
+        }
+        if !body.is_empty() {
+            failed += self.client.bulk_request(&self.index, body).await?;
+        }
+        Ok(failed)
+    }
+}
+
+#[async_trait]
+impl SearchIndex for Elastic {
+    async fn index(&self, links: &[ElasticLink]) -> Result<usize> {
+        info!("Adding {} links to {}", links.len(), self.index);
+        self.bulk(links.iter().map(BulkAction::Index)).await
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<usize> {
+        info!("Removing {} links from {}", ids.len(), self.index);
+        self.bulk(ids.iter().map(|id| BulkAction::Delete(id))).await
+    }
+
+    async fn delete_opendirectory(&self, od: &str) -> Result<()> {
+        info!("Removing links of OD {} from {}", od, self.index);
+        let query = json!({"query": {"term": {"opendirectory": od}}});
+        let deleted = self.client.delete_by_query(&self.index, query).await?;
+        info!("Removed {} links of OD {}", deleted, od);
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<u64> {
+        self.client.count(&self.index).await
+    }
+
+    fn ids(&self) -> BoxStream<'_, Result<String>> {
+        let pages = futures::stream::try_unfold(Some(None), move |after| async move {
+            let after: Option<serde_json::Value> = match after {
+                Some(after) => after,
+                None => return Ok::<_, anyhow::Error>(None),
+            };
+            let mut query = json!({
+                "size": ID_PAGE_SIZE,
+                "_source": false,
+                "sort": [{"id": "asc"}]
+            });
+            if let Some(after) = after {
+                query["search_after"] = after;
+            }
+            let response = self
+                .client
+                .request_ok(
+                    Method::POST,
+                    &format!("{}/_search", self.index),
+                    Some(query),
+                )
+                .await?;
+            let hits = response["hits"]["hits"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            let ids: Vec<Result<String>> = hits
+                .iter()
+                .filter_map(|hit| hit["_id"].as_str().map(|id| Ok(id.to_string())))
+                .collect();
+            let next = match hits.last() {
+                Some(last) if hits.len() == ID_PAGE_SIZE => Some(Some(last["sort"].clone())),
+                _ => None,
+            };
+            Ok(Some((futures::stream::iter(ids), next)))
+        });
+        pages.try_flatten().boxed()
+    }
+
+    async fn rebuild(&self, resume: Option<&str>) -> Result<Box<dyn SearchIndex>> {
+        let index = match resume {
+            Some(index) if self.client.exists(index).await? => {
+                info!("Resuming index {}", index);
+                index.to_string()
+            }
+            _ => self.client.create_versioned_index().await?,
+        };
+        Ok(Box::new(Self {
+            client: self.client.clone(),
+            index,
+            retention: self.retention,
+            rebuilding: true,
+        }))
+    }
+
+    fn name(&self) -> String {
+        self.index.clone()
+    }
+
+    async fn publish(&self, expected: u64) -> Result<()> {
+        if !self.rebuilding {
+            return Ok(());
+        }
+        let indexed = self.count().await?;
+        if indexed != expected {
+            // A resumed export would only add to the wrong documents
+            self.client
+                .request_ok(Method::DELETE, &self.index, None)
+                .await?;
+            bail!(
+                "{} contained {} documents, but {} were expected. Deleted it and kept the previous index",
+                self.index,
+                indexed,
+                expected
+            );
+        }
+        self.client.swap_alias(&self.index).await?;
+        self.client.remove_old_indices(self.retention).await
+    }
+
+    async fn needs_rebuild(&self) -> Result<bool> {
+        self.client.has_outdated_mappings(&self.index).await
+    }
+}
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct ElasticLink {
-    #[serde(skip_serializing)]
+    /// Also stored as a field, since sorting by `_id` isn't supported
     pub id: String,
     pub url: String,
     pub filename: String,
     pub extension: Option<String>,
+    /// Root URL of the OD this link belongs to
+    pub opendirectory: String,
+    pub host: Option<String>,
+    pub tld: Option<String>,
+    /// Decoded directories between the host and the file
+    pub segments: Vec<String>,
+    /// Name of the directory containing the file
+    pub parent: Option<String>,
+    pub size: Option<i64>,
+    pub last_modified: Option<chrono::DateTime<chrono::Utc>>,
+    pub category: Option<String>,
+    /// Whether the OD was alive and the link reachable when it was last checked
+    pub alive: bool,
 }
 
-impl From<db::Link> for ElasticLink {
-    fn from(l: Link) -> Self {
-        Self {
-            id: l.id.unwrap().to_string(),
-            filename: PathBuf::from(&l.url)
-                .file_name()
-                .unwrap()
-                .to_string_lossy()
-                .to_string(),
-            extension: PathBuf::from(&l.url)
-                .extension()
-                .map(|e| e.to_string_lossy().into()),
+impl ElasticLink {
+    /// Builds the document of a link, whose OD is alive if `od_alive` is set.
+    /// Fails for links which weren't saved yet, since documents are identified by the link's ID.
+    pub fn new(l: Link, od_alive: bool) -> Result<Self> {
+        let id = match &l.id {
+            Some(id) => id.to_string(),
+            None => bail!("Link {} has no ID, it can't be indexed", l.url),
+        };
+        let url = Url::parse(link_path(&l.url)).ok();
+        let filename = match &url {
+            Some(url) => file_name(url),
+            None => raw_file_name(link_path(&l.url)),
+        };
+        let extension = filename.as_deref().and_then(extension);
+        let host = shared::host_of(&l.url);
+        // For links without a path, the host is the closest thing to a name
+        let filename = filename.or_else(|| host.clone()).unwrap_or_default();
+        let segments = url.as_ref().map(directory_segments).unwrap_or_default();
+        let alive = od_alive && !l.is_missing();
+        Ok(Self {
+            id,
+            filename,
+            category: l
+                .category
+                .or_else(|| extension.as_deref().and_then(Category::from_extension))
+                .map(|c| c.to_string()),
+            extension,
+            tld: host.as_deref().and_then(tld),
+            host,
+            parent: segments.last().cloned(),
+            segments,
+            size: l.size,
+            last_modified: l.last_modified.map(|d| d.0),
+            alive,
+            opendirectory: l.opendirectory,
             url: l.url,
-        }
+        })
     }
 }
 
-pub async fn add_links_from_db(opt: &Opt, db: &db::Database, od: &str) -> Result<()> {
-    db.get_links(&od)
-        .await?
-        .filter_map(|l| async { Some(l.ok()?.into()) })
-        .chunks(50_000)
-        .for_each(|chunk| async move {
-            if let Err(e) = add_bulk(opt, &chunk) {
-                warn!("Failed to add links to Elasticsearch: {}", e)
-            }
-        })
-        .await;
-    Ok(())
+/// The extension of the file a link points to.
+pub fn link_extension(link: &str) -> Option<String> {
+    let file_name = match Url::parse(link_path(link)) {
+        Ok(url) => file_name(&url),
+        Err(_) => raw_file_name(link_path(link)),
+    };
+    file_name.as_deref().and_then(extension)
 }
 
-pub fn add_bulk(opt: &Opt, links: &[ElasticLink]) -> Result<()> {
-    info!("Adding {} links to Elasticsearch", links.len());
-    for chunk in links.chunks(5_000) {
-        let mut body = BulkBody::default();
-        for link in chunk {
-            body.items.push(BulkAction::Index(link.clone()));
-        }
+/// Query parameters which download scripts (e.g. `download.php?file=a.zip`) use for the file.
+const FILE_PARAMETERS: [&str; 4] = ["file", "filename", "name", "f"];
+
+/// The decoded name of the file a URL points to, or the last directory if it points to one.
+fn file_name(url: &Url) -> Option<String> {
+    let from_query = url
+        .query_pairs()
+        .find(|(key, _)| FILE_PARAMETERS.contains(&key.to_ascii_lowercase().as_str()))
+        .and_then(|(_, value)| last_segment(&value).map(String::from));
+    from_query.or_else(|| {
+        url.path_segments()?
+            .rfind(|s| !s.is_empty())
+            .map(|s| percent_decode_str(s).decode_utf8_lossy().to_string())
+    })
+}
+
+/// For URLs the url crate can't parse, strips the query and fragment by hand.
+fn raw_file_name(url: &str) -> Option<String> {
+    let path = url.split(&['?', '#'][..]).next().unwrap_or_default();
+    let name = last_segment(path)?;
+    Some(percent_decode_str(name).decode_utf8_lossy().to_string())
+}
 
-        bulk_request(opt, body)?;
+fn last_segment(path: &str) -> Option<&str> {
+    path.rsplit(&['/', '\\'][..]).find(|s| !s.is_empty())
+}
+
+/// The extension of a file name. Dotfiles and suffixes which can't be extensions
+/// (e.g. `Vol. 2 (1999)`) have none.
+fn extension(file_name: &str) -> Option<String> {
+    let (stem, extension) = file_name.rsplit_once('.')?;
+    if stem.is_empty()
+        || extension.is_empty()
+        || extension.len() > 10
+        || !extension.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return None;
     }
-    Ok(())
+    Some(extension.to_string())
 }
 
-pub fn remove_bulk(opt: &Opt, ids: &[String]) -> Result<()> {
-    info!("Removing {} links from Elasticsearch", ids.len());
-    for chunk in ids.chunks(5_000) {
-        let mut body = BulkBody::default();
-        for id in chunk {
-            body.items.push(BulkAction::Delete(id.to_string()));
-        }
+/// The percent-decoded directory names in a URL's path, without the file name.
+fn directory_segments(url: &Url) -> Vec<String> {
+    let mut segments: Vec<String> = match url.path_segments() {
+        Some(segments) => segments
+            .filter(|s| !s.is_empty())
+            .map(|s| percent_decode_str(s).decode_utf8_lossy().to_string())
+            .collect(),
+        None => return vec![],
+    };
+    if !url.path().ends_with('/') {
+        segments.pop();
+    }
+    segments
+}
+
+/// The top-level domain of a host, unless it's an IP address.
+fn tld(host: &str) -> Option<String> {
+    if host.parse::<std::net::IpAddr>().is_ok() || host.starts_with('[') {
+        return None;
+    }
+    host.rsplit('.').next().map(|t| t.to_ascii_lowercase())
+}
 
-        bulk_request(opt, body)?;
+/// Strips FTP typecodes (e.g. `;type=i`) which would otherwise end up in the extension.
+fn link_path(url: &str) -> &str {
+    if crate::ftp::is_ftp(url) {
+        if let Some(index) = url.rfind(";type=") {
+            return &url[..index];
+        }
     }
-    Ok(())
+    url
 }
 
-pub enum BulkAction {
-    Delete(String),
-    Index(ElasticLink),
+pub enum BulkAction<'a> {
+    Delete(&'a str),
+    Index(&'a ElasticLink),
 }
 
+impl BulkAction<'_> {
+    /// The NDJSON lines of this action, including the trailing newline
+    fn serialize(&self) -> String {
+        match self {
+            BulkAction::Delete(id) => format!("{}\n", json!({"delete": {"_id": id}})),
+            BulkAction::Index(link) => format!(
+                "{}\n{}\n",
+                json!({"index": {"_id": link.id}}),
+                serde_json::to_string(link).unwrap()
+            ),
+        }
+    }
+}
+
+/// Serialized actions of a single bulk request.
+#[derive(Default)]
 pub struct BulkBody {
-    pub items: Vec<BulkAction>,
+    items: Vec<String>,
+    bytes: usize,
 }
 
-impl Default for BulkBody {
-    fn default() -> Self {
-        BulkBody { items: vec![] }
+impl BulkBody {
+    /// Whether another serialized action fits into this request, so requests stay below
+    /// Elasticsearch's `http.max_content_length`. An empty body fits any action.
+    fn fits(&self, item: &str) -> bool {
+        self.items.is_empty()
+            || (self.items.len() < MAX_BULK_ITEMS && self.bytes + item.len() <= MAX_BULK_BYTES)
+    }
+
+    fn push(&mut self, item: String) {
+        self.bytes += item.len();
+        self.items.push(item);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    fn to_body(&self) -> String {
+        self.items.concat()
     }
 }
 
-impl ToString for BulkBody {
-    fn to_string(&self) -> String {
-        let mut buffer = String::new();
-        for action in &self.items {
-            let to_add = match action {
-                BulkAction::Delete(id) => {
-                    format!("{}\n", json!({"delete": {"_id": id}}).to_string())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    fn client(address: std::net::SocketAddr) -> Client {
+        nodes(&[address])
+    }
+
+    fn nodes(addresses: &[std::net::SocketAddr]) -> Client {
+        let urls = addresses.iter().map(|a| format!("http://{}", a)).collect();
+        Client::new(&Config {
+            connection: shared::elastic::Config {
+                urls,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn secrets_are_not_printed() {
+        let config = Config {
+            connection: shared::elastic::Config {
+                pass: "hunter2".to_string(),
+                api_key: Some("c2VjcmV0".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let printed = format!("{:?}", config);
+        assert!(!printed.contains("hunter2"));
+        assert!(!printed.contains("c2VjcmV0"));
+        assert!(printed.contains("127.0.0.1:9200"));
+    }
+
+    fn delete_all(ids: &[&str]) -> BulkBody {
+        let mut body = BulkBody::default();
+        for id in ids {
+            body.push(BulkAction::Delete(id).serialize());
+        }
+        body
+    }
+
+    fn link(url: &str) -> Link {
+        Link {
+            id: Some(wither::bson::oid::ObjectId::new()),
+            opendirectory: "http://od.test/".to_string(),
+            url: url.to_string(),
+            missing: 0,
+            size: None,
+            last_modified: None,
+            category: None,
+        }
+    }
+
+    #[test]
+    fn links_are_alive_if_their_od_is() {
+        assert!(
+            ElasticLink::new(link("http://od.test/a.mkv"), true)
+                .unwrap()
+                .alive
+        );
+        assert!(
+            !ElasticLink::new(link("http://od.test/a.mkv"), false)
+                .unwrap()
+                .alive
+        );
+        let mut missing = link("http://od.test/a.mkv");
+        missing.missing = shared::MISSING_LINK_THRESHOLD;
+        assert!(!ElasticLink::new(missing, true).unwrap().alive);
+    }
+
+    #[test]
+    fn file_names_and_extensions() {
+        for (url, filename, extension) in &[
+            (
+                "http://od.test/Movies/Heat%20(1995).mkv",
+                "Heat (1995).mkv",
+                Some("mkv"),
+            ),
+            ("http://od.test/Movies/", "Movies", None),
+            ("http://od.test/a/b/../c.mkv", "c.mkv", Some("mkv")),
+            ("http://od.test/..", "od.test", None),
+            ("http://od.test/", "od.test", None),
+            ("http://od.test/dir%2Fname.mp4", "dir/name.mp4", Some("mp4")),
+            (
+                "http://od.test/download.php?file=Season%201/Episode%2001.mkv",
+                "Episode 01.mkv",
+                Some("mkv"),
+            ),
+            (
+                "http://od.test/get?id=3&FileName=a.pdf",
+                "a.pdf",
+                Some("pdf"),
+            ),
+            (
+                "http://od.test/Album/01%20-%20Intro.flac#t=30",
+                "01 - Intro.flac",
+                Some("flac"),
+            ),
+            (
+                "http://od.test/backup.tar.gz?download=1",
+                "backup.tar.gz",
+                Some("gz"),
+            ),
+            (
+                "ftp://ftp.od.test/pub/debian.iso;type=i",
+                "debian.iso",
+                Some("iso"),
+            ),
+            ("ftp://ftp.od.test/pub/;type=d", "pub", None),
+            (
+                "http://od.test/Some%20Show%20Vol.%202%20(1999)/",
+                "Some Show Vol. 2 (1999)",
+                None,
+            ),
+            ("http://od.test/.htaccess", ".htaccess", None),
+            (
+                "http://od test/[bad]/a%20b.mp3?x=1#y",
+                "a b.mp3",
+                Some("mp3"),
+            ),
+        ] {
+            let document = ElasticLink::new(link(url), true).unwrap();
+            assert_eq!(document.filename, *filename, "{}", url);
+            assert_eq!(document.extension.as_deref(), *extension, "{}", url);
+            assert_eq!(link_extension(url).as_deref(), *extension, "{}", url);
+        }
+    }
+
+    #[test]
+    fn unsaved_links_have_no_document() {
+        let mut unsaved = link("http://od.test/a.mkv");
+        unsaved.id = None;
+        assert!(ElasticLink::new(unsaved, true).is_err());
+    }
+
+    #[async_std::test]
+    async fn bulk_request_retries_rejected_items() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let server = testing::http_server(move |request| {
+            let mut received = received.lock().unwrap();
+            let retry = !received.is_empty();
+            received.push((request.path.clone(), request.body.clone()));
+            let items: Vec<_> = request
+                .body
+                .lines()
+                .map(|line| {
+                    let id = serde_json::from_str::<serde_json::Value>(line).unwrap()["delete"]
+                        ["_id"]
+                        .clone();
+                    let (status, error) = match id.as_str().unwrap() {
+                        "busy" if !retry => (429, Some("es_rejected_execution_exception")),
+                        "bad" => (400, Some("illegal_argument_exception")),
+                        _ => (200, None),
+                    };
+                    let error = error.map(|kind| json!({ "type": kind }));
+                    json!({"delete": {"_id": id, "status": status, "error": error}})
+                })
+                .collect();
+            let errors = items.iter().any(|i| !i["delete"]["error"].is_null());
+            testing::response(
+                "200 OK",
+                &json!({"took": 1, "errors": errors, "items": items}).to_string(),
+            )
+        });
+
+        let start = Instant::now();
+        let failed = client(server)
+            .bulk_request("links-1", delete_all(&["ok", "busy", "bad"]))
+            .await
+            .unwrap();
+
+        assert_eq!(failed, 1);
+        assert!(start.elapsed() >= INITIAL_BACKOFF);
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].0, "/links-1/_bulk");
+        assert_eq!(requests[0].1.lines().count(), 3);
+        assert_eq!(requests[1].1, BulkAction::Delete("busy").serialize());
+    }
+
+    #[async_std::test]
+    async fn publish_deletes_incomplete_indices() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let server = testing::http_server(move |request| {
+            received
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", request.method, request.path));
+            match request.path.as_str() {
+                "/links-1/_count" => testing::response("200 OK", r#"{"count": 2}"#),
+                _ => testing::response("200 OK", r#"{"acknowledged": true}"#),
+            }
+        });
+        let rebuilt = Elastic {
+            client: Arc::new(client(server)),
+            index: "links-1".to_string(),
+            retention: 2,
+            rebuilding: true,
+        };
+
+        assert!(rebuilt.publish(3).await.is_err());
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                "POST /links-1/_refresh",
+                "GET /links-1/_count",
+                "DELETE /links-1"
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn only_old_versioned_indices_of_the_alias_are_removed() {
+        let deleted = Arc::new(Mutex::new(vec![]));
+        let received = deleted.clone();
+        let server = testing::http_server(move |request| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/_cat/indices/links-*?format=json&h=index") => testing::response(
+                    "200 OK",
+                    r#"[{"index": "links-20260101000000"}, {"index": "links-20260102000000"},
+                        {"index": "links-20260103000000"}, {"index": "links-staging-20260104000000"},
+                        {"index": "links-staging-20260105000000"}]"#,
+                ),
+                ("GET", "/_alias/links") => {
+                    testing::response("200 OK", r#"{"links-20260102000000": {}}"#)
+                }
+                ("DELETE", index) => {
+                    received.lock().unwrap().push(index.to_string());
+                    testing::response("200 OK", r#"{"acknowledged": true}"#)
                 }
-                BulkAction::Index(link) => format!(
-                    "{}\n{}",
-                    json!({"index": {"_id": link.id}}).to_string(),
-                    serde_json::to_string(&link).unwrap()
+                _ => testing::response("404 Not Found", "{}"),
+            }
+        });
+
+        client(server).remove_old_indices(1).await.unwrap();
+        assert_eq!(*deleted.lock().unwrap(), vec!["/links-20260101000000"]);
+    }
+
+    #[async_std::test]
+    async fn outdated_templates_are_only_replaced_when_upgrading() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        let server = testing::http_server(move |request| {
+            received.lock().unwrap().push(request.method.clone());
+            match request.method.as_str() {
+                "GET" => testing::response(
+                    "200 OK",
+                    r#"{"index_templates": [{"name": "links", "index_template": {"version": 2}}]}"#,
                 ),
-            };
-            buffer = format!("{}{}\n", buffer, to_add);
-        }
-        buffer
+                _ => testing::response("200 OK", r#"{"acknowledged": true}"#),
+            }
+        });
+
+        assert!(client(server).ensure_template().await.is_err());
+        assert_eq!(*requests.lock().unwrap(), vec!["GET"]);
+
+        let mut upgrading = client(server);
+        upgrading.upgrade_template = true;
+        upgrading.ensure_template().await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), vec!["GET", "GET", "PUT"]);
     }
-}
 
-pub fn bulk_request(opt: &Opt, body: BulkBody) -> Result<()> {
-    let mut response = isahc::http::Request::put(format!("{}/links/_bulk", opt.elastic_url))
-        .header(CONTENT_TYPE, "application/json")
-        .authentication(Authentication::basic())
-        .credentials(Credentials::new("elastic", opt.elastic_pass.clone()))
-        .body(body.to_string())?
-        .send()?;
-    if !response.status().is_success() {
-        let mut buffer = String::new();
-        response.into_body().read_to_string(&mut buffer)?;
-        error!("Got non-success status code, response was\n {}", buffer);
-    } else {
-        // Cleanly read & drop the response to avoid warnings
-        // https://github.com/sagebind/isahc/issues/270#issuecomment-749083844
-        let _ = response.copy_to(std::io::sink());
+    #[async_std::test]
+    async fn indices_from_older_templates_need_a_rebuild() {
+        let server = testing::http_server(|request| match request.path.as_str() {
+            "/links" | "/old" => testing::response("200 OK", "{}"),
+            "/old/_mapping" => {
+                testing::response("200 OK", r#"{"links-1": {"mappings": {"properties": {}}}}"#)
+            }
+            "/links/_mapping" => testing::response(
+                "200 OK",
+                &json!({"links-2": {"mappings": {"_meta": {"version": TEMPLATE_VERSION}}}})
+                    .to_string(),
+            ),
+            _ => testing::response("404 Not Found", "{}"),
+        });
+        let client = client(server);
+
+        assert!(!client.has_outdated_mappings("links").await.unwrap());
+        assert!(!client.has_outdated_mappings("missing").await.unwrap());
+        assert!(client.has_outdated_mappings("old").await.unwrap());
+    }
+
+    #[async_std::test]
+    async fn bulk_request_retries_busy_clusters() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counter = attempts.clone();
+        let server = testing::http_server(move |_| {
+            if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                return testing::response("429 Too Many Requests", "{}");
+            }
+            testing::response("200 OK", r#"{"took": 1, "errors": false, "items": []}"#)
+        });
+
+        let failed = client(server)
+            .bulk_request("links-1", delete_all(&["ok"]))
+            .await
+            .unwrap();
+
+        assert_eq!(failed, 0);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn unreachable_nodes_are_skipped() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let server = testing::http_server(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            testing::response("200 OK", r#"{"acknowledged": true}"#)
+        });
+
+        let client = nodes(&[testing::closed_port(), server]);
+        client.request_ok(Method::GET, "", None).await.unwrap();
+        client.request_ok(Method::GET, "", None).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
     }
-    Ok(())
 }
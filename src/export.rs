@@ -0,0 +1,190 @@
+use crate::elastic::ElasticLink;
+use crate::reconcile;
+use crate::search::SearchIndex;
+use crate::Opt;
+use anyhow::{bail, Result};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use futures::StreamExt;
+use indicatif::{ProgressBar, ProgressStyle};
+use shared::db::{Database, ExportRun};
+use structopt::StructOpt;
+use wither::bson::doc;
+use wither::bson::oid::ObjectId;
+use wither::Model;
+
+/// How many links are indexed at once
+const CHUNK_SIZE: usize = 5_000;
+/// How many chunks are indexed at once
+const CONCURRENCY: usize = 4;
+
+/// Limits an export to some links. Scoped exports update the live index instead of replacing it.
+#[derive(StructOpt, Debug, Clone, Default)]
+pub struct ExportScope {
+    /// Only export links of this OD
+    #[structopt(long)]
+    pub od: Option<String>,
+
+    /// Only export links added since this date (YYYY-MM-DD)
+    #[structopt(long, parse(try_from_str = parse_date))]
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl ExportScope {
+    pub fn is_full(&self) -> bool {
+        self.od.is_none() && self.since.is_none()
+    }
+}
+
+fn parse_date(date: &str) -> Result<DateTime<Utc>> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")?;
+    Ok(Utc.from_utc_datetime(&date.and_hms(0, 0, 0)))
+}
+
+/// The smallest ID of a document created at `time`, as IDs start with their creation time.
+fn first_id_at(time: DateTime<Utc>) -> ObjectId {
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&(time.timestamp() as u32).to_be_bytes());
+    ObjectId::with_bytes(bytes)
+}
+
+/// Exports searchable links to the search index in ID order.
+///
+/// The last exported ID is saved after each chunk, so an interrupted export with the same scope
+/// continues from there. A chunk the search index can't take interrupts it as well.
+///
+/// Full exports go into a new index, which replaces the live one once complete. Before that,
+/// it's reconciled with the database, since changes made while exporting only went to the live
+/// index.
+pub async fn export(opt: &Opt, db: &Database, scope: &ExportScope) -> Result<()> {
+    let since = scope.since.map(Into::into);
+    let mut run = match db
+        .unfinished_export_run(scope.od.as_deref(), since.as_ref())
+        .await?
+    {
+        Some(run) => {
+            info!("Resuming export started {}", run.started.0);
+            run
+        }
+        None => {
+            info!("Exporting links to the search index");
+            ExportRun {
+                id: None,
+                opendirectory: scope.od.clone(),
+                since,
+                index: None,
+                checkpoint: None,
+                exported: 0,
+                failed: 0,
+                started: Utc::now().into(),
+                finished: None,
+            }
+        }
+    };
+
+    let rebuilt;
+    let target: &dyn SearchIndex = if scope.is_full() {
+        rebuilt = opt.search.rebuild(run.index.as_deref()).await?;
+        let name = rebuilt.name();
+        if run.index.as_ref() != Some(&name) {
+            // The previous index is gone, so everything has to be exported again
+            run.index = Some(name);
+            run.checkpoint = None;
+            run.exported = 0;
+            run.failed = 0;
+        }
+        rebuilt.as_ref()
+    } else {
+        &*opt.search
+    };
+    run.save(&db.db, None).await?;
+
+    let mut filter = doc! {};
+    if let Some(od) = &scope.od {
+        filter.insert("opendirectory", od);
+    }
+    let mut id = doc! {};
+    if let Some(since) = scope.since {
+        id.insert("$gte", first_id_at(since));
+    }
+    if let Some(checkpoint) = &run.checkpoint {
+        id.insert("$gt", checkpoint.clone());
+    }
+    if !id.is_empty() {
+        filter.insert("_id", id);
+    }
+
+    let total = db.count_searchable_links(filter.clone()).await?;
+    let pb = ProgressBar::new(total).with_style(
+        ProgressStyle::default_bar().template("{percent}%, ETA {eta}   {wide_bar}   {pos}/{len}"),
+    );
+    pb.enable_steady_tick(100);
+
+    let alive_ods = db.alive_opendirectory_urls().await?;
+    let alive_ods = &alive_ods;
+    // Chunks are indexed concurrently, but finish in order, so the checkpoint never skips any
+    let chunks = db
+        .get_searchable_links(filter)
+        .await?
+        .filter_map(|l| async { l.ok() })
+        .chunks(CHUNK_SIZE)
+        .map(|chunk| async move {
+            let checkpoint = chunk.last().and_then(|l| l.id.clone());
+            let total = chunk.len();
+            let links: Vec<ElasticLink> = chunk
+                .into_iter()
+                .filter_map(|l| {
+                    let od_alive = alive_ods.contains(&l.opendirectory);
+                    ElasticLink::new(l, od_alive)
+                        .map_err(|e| warn!("Skipping link: {}", e))
+                        .ok()
+                })
+                .collect();
+            let failed = target
+                .index(&links)
+                .await
+                .map(|failed| failed + total - links.len());
+            (checkpoint, total, failed)
+        })
+        .buffered(CONCURRENCY);
+    futures::pin_mut!(chunks);
+
+    while let Some((checkpoint, total, failed)) = chunks.next().await {
+        // The checkpoint stays before a chunk which wasn't indexed, so resuming retries it
+        let failed = match failed {
+            Ok(failed) => failed,
+            Err(e) => {
+                pb.abandon();
+                bail!(
+                    "Error exporting links to the search index, the export can be resumed: {}",
+                    e
+                );
+            }
+        };
+        run.checkpoint = checkpoint;
+        run.exported += (total - failed) as i64;
+        run.failed += failed as i64;
+        run.save(&db.db, None).await?;
+        pb.inc(total as u64);
+    }
+    pb.finish();
+
+    let mut expected = run.exported as u64;
+    if scope.is_full() {
+        // Links which were added or removed meanwhile only changed the live index, and links
+        // which failed are retried
+        let report = reconcile::reconcile(db, target, true).await?;
+        run.exported = (report.links - report.failed_to_add) as i64;
+        run.failed = report.failed_to_add as i64;
+        run.save(&db.db, None).await?;
+        expected = report.links;
+    }
+
+    info!(
+        "Exported {} searchable links, {} failed",
+        run.exported, run.failed
+    );
+    // An index which fails to publish is incomplete, so it isn't resumed
+    run.finished = Some(Utc::now().into());
+    run.save(&db.db, None).await?;
+    target.publish(expected).await
+}
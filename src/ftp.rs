@@ -0,0 +1,136 @@
+use crate::proxy::Failure;
+use crate::Opt;
+use curl::easy::Easy;
+use std::os::raw::c_long;
+use std::time::Duration;
+
+/// Whether a link points to an FTP or FTPS server. `ftps://` is implicit FTPS, `ftp://` is
+/// plain FTP unless `--ftps-explicit` requires upgrading with AUTH TLS.
+pub fn is_ftp(link: &str) -> bool {
+    let link = link.to_ascii_lowercase();
+    link.starts_with("ftp://") || link.starts_with("ftps://")
+}
+
+/// Checks whether an FTP file or directory exists, without downloading it.
+///
+/// isahc only speaks HTTP, so this uses curl directly on a blocking thread.
+pub async fn check(opt: &Opt, link: &str, timeout: Duration) -> Result<(), curl::Error> {
+    let proxy = opt.proxies.for_link(link);
+    let proxy_uri = proxy.as_ref().map(|(_, uri)| uri.to_string());
+    let owned_link = link.to_string();
+    let explicit_tls = opt.ftps_explicit;
+    let result = async_std::task::spawn_blocking(move || {
+        request(&owned_link, proxy_uri, timeout, explicit_tls)
+    })
+    .await;
+
+    if let Some((index, _)) = proxy {
+        let failure = result.as_ref().err().map(Failure::from);
+        opt.proxies.report_result(index, failure).await;
+    }
+
+    if let Err(e) = &result {
+        debug!("FTP check of {} failed: {}", link, e);
+    }
+    result
+}
+
+fn request(
+    link: &str,
+    proxy: Option<String>,
+    timeout: Duration,
+    explicit_tls: bool,
+) -> Result<(), curl::Error> {
+    let mut easy = Easy::new();
+    easy.url(link)?;
+    easy.connect_timeout(timeout)?;
+    easy.timeout(timeout)?;
+    // Only change into the directory or stat the file, instead of transferring anything
+    easy.nobody(true)?;
+    if explicit_tls {
+        require_tls(&mut easy)?;
+    }
+    easy.ssl_verify_peer(false)?;
+    easy.ssl_verify_host(false)?;
+    if let Some(proxy) = proxy {
+        easy.proxy(&proxy)?;
+    }
+    easy.perform()
+}
+
+/// Makes curl upgrade the control and data connections with AUTH TLS, failing if the server
+/// doesn't support it. The curl crate has no wrapper for this option.
+fn require_tls(easy: &mut Easy) -> Result<(), curl::Error> {
+    let code = unsafe {
+        curl_sys::curl_easy_setopt(
+            easy.raw(),
+            curl_sys::CURLOPT_USE_SSL,
+            curl_sys::CURLUSESSL_ALL as c_long,
+        )
+    };
+    if code == curl_sys::CURLE_OK {
+        Ok(())
+    } else {
+        Err(curl::Error::new(code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_links::link_is_reachable;
+    use crate::testing;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn server() -> String {
+        let address = testing::ftp_server(
+            &["/pub", "/pub/iso"],
+            &[("/pub/iso/debian-10.7.0-amd64-netinst.iso", 349_175_808)],
+        );
+        format!("ftp://{}", address)
+    }
+
+    #[async_std::test]
+    async fn checks_directories_and_files() {
+        let server = server();
+        let opt = testing::opt();
+        for path in &[
+            "/pub/",
+            "/pub/iso/",
+            "/pub/iso/debian-10.7.0-amd64-netinst.iso",
+            "/pub/iso/debian-10.7.0-amd64-netinst.iso;type=i",
+        ] {
+            let link = format!("{}{}", server, path);
+            assert!(check(&opt, &link, TIMEOUT).await.is_ok(), "{}", link);
+        }
+        for path in &[
+            "/private/",
+            "/pub/iso/missing.iso",
+            "/pub/missing.iso;type=i",
+        ] {
+            let link = format!("{}{}", server, path);
+            assert!(check(&opt, &link, TIMEOUT).await.is_err(), "{}", link);
+        }
+    }
+
+    #[async_std::test]
+    async fn checks_ftp_ods_like_http_ones() {
+        let server = server();
+        let opt = testing::opt();
+        let link = format!("{}/pub/iso/", server);
+        assert!(link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        let link = format!("{}/private/", server);
+        assert!(!link_is_reachable(&opt, &link, TIMEOUT, false).await);
+    }
+
+    #[async_std::test]
+    async fn explicit_tls_is_required() {
+        let server = server();
+        let mut opt = testing::opt();
+        opt.ftps_explicit = true;
+        let link = format!("{}/pub/", server);
+        let error = check(&opt, &link, TIMEOUT).await.unwrap_err();
+        assert!(error.is_use_ssl_failed(), "{}", error);
+    }
+}
@@ -0,0 +1,209 @@
+use anyhow::{Context, Result};
+use isahc::http::header::USER_AGENT;
+use isahc::http::request::Builder;
+use isahc::http::Method;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Site-specific quirks, loaded from a JSON file so they can be changed without a recompile.
+///
+/// Example:
+/// ```json
+/// [{
+///     "host": "driveindex\\.ga",
+///     "rewrite_host": "hashhackers.com",
+///     "headers": { "Referer": "{url}" }
+/// }]
+/// ```
+/// `{url}` in header and cookie values is replaced with the original URL.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct HostRule {
+    /// Regex which is matched against the host of a URL
+    pub host: String,
+    /// Replacement for the matched part of the host
+    pub rewrite_host: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub cookies: HashMap<String, String>,
+    pub user_agent: Option<String>,
+    /// HTTP method to use instead of HEAD
+    pub method: Option<String>,
+    /// Overrides `--host-concurrency`
+    pub concurrency: Option<usize>,
+    /// Overrides `--host-delay`, in milliseconds
+    pub delay: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HostRules {
+    rules: Vec<(Regex, HostRule)>,
+}
+
+impl HostRules {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            warn!(
+                "Host rules file {} does not exist, not using any rules",
+                path.to_string_lossy()
+            );
+            return Ok(Self::default());
+        }
+
+        let file = std::fs::File::open(path)?;
+        let rules: Vec<HostRule> = serde_json::from_reader(file)
+            .with_context(|| format!("Failed to parse {}", path.to_string_lossy()))?;
+        let rules = Self::new(rules)?;
+        info!("Loaded {} host rules", rules.rules.len());
+        Ok(rules)
+    }
+
+    /// Compiles the host patterns of `rules`, checking their methods as well.
+    pub fn new(rules: Vec<HostRule>) -> Result<Self> {
+        let mut compiled = vec![];
+        for rule in rules {
+            let regex = Regex::new(&rule.host)
+                .with_context(|| format!("Invalid host pattern '{}'", rule.host))?;
+            if let Some(method) = &rule.method {
+                Method::from_bytes(method.as_bytes())
+                    .with_context(|| format!("Invalid method '{}' for '{}'", method, rule.host))?;
+            }
+            compiled.push((regex, rule));
+        }
+        Ok(Self { rules: compiled })
+    }
+
+    /// Returns the first rule matching the host of `link`, if any.
+    pub fn find(&self, link: &str) -> Option<(&Regex, &HostRule)> {
+        let host = shared::host_of(link)?;
+        self.rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(&host))
+            .map(|(regex, rule)| (regex, rule))
+    }
+
+    /// Applies the matching rule (if any) to a request for `link`.
+    pub fn apply(&self, link: &str, mut builder: Builder) -> Builder {
+        let (regex, rule) = match self.find(link) {
+            Some(r) => r,
+            None => return builder,
+        };
+
+        if let Some(rewrite_host) = &rule.rewrite_host {
+            if let Ok(mut url) = url::Url::parse(link) {
+                let host = url.host_str().unwrap_or_default().to_string();
+                let new_host = regex.replace(&host, rewrite_host.as_str());
+                if url.set_host(Some(&new_host)).is_ok() {
+                    builder = builder.uri(url.as_str());
+                }
+            }
+        }
+        if let Some(method) = &rule.method {
+            builder = builder.method(method.as_str());
+        }
+        if let Some(user_agent) = &rule.user_agent {
+            // Replace the default user agent instead of sending two
+            if let Some(headers) = builder.headers_mut() {
+                headers.remove(USER_AGENT);
+            }
+            builder = builder.header(USER_AGENT, user_agent.as_str());
+        }
+        for (name, value) in &rule.headers {
+            builder = builder.header(name.as_str(), value.replace("{url}", link));
+        }
+        if !rule.cookies.is_empty() {
+            let cookies: Vec<String> = rule
+                .cookies
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value.replace("{url}", link)))
+                .collect();
+            builder = builder.header("Cookie", cookies.join("; "));
+        }
+
+        builder
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isahc::http::Request;
+
+    fn build(rules: &HostRules, link: &str) -> Request<()> {
+        let builder = Request::head(link).header(USER_AGENT, "odcrawler");
+        rules.apply(link, builder).body(()).unwrap()
+    }
+
+    #[test]
+    fn shipped_rules_rewrite_driveindex() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("host_rules.json");
+        let rules = HostRules::load(&path).unwrap();
+
+        let link = "https://a.driveindex.ga/Movies/Heat%20(1995).mkv";
+        let request = build(&rules, link);
+        assert_eq!(
+            request.uri(),
+            "https://a.hashhackers.com/Movies/Heat%20(1995).mkv"
+        );
+        assert_eq!(request.headers()["Referer"], link);
+        assert_eq!(request.method(), Method::HEAD);
+
+        let link = "https://od.test/driveindex.ga/a.mkv";
+        let request = build(&rules, link);
+        assert_eq!(request.uri(), link);
+        assert!(request.headers().get("Referer").is_none());
+    }
+
+    #[test]
+    fn rules_set_cookies_user_agent_and_method() {
+        let rules = HostRules::new(vec![HostRule {
+            host: r"^(www\.)?od\.test$".to_string(),
+            cookies: vec![("origin".to_string(), "{url}".to_string())]
+                .into_iter()
+                .collect(),
+            user_agent: Some("Mozilla/5.0".to_string()),
+            method: Some("GET".to_string()),
+            ..Default::default()
+        }])
+        .unwrap();
+
+        let link = "http://www.od.test/files/";
+        let request = build(&rules, link);
+        assert_eq!(request.method(), Method::GET);
+        assert_eq!(
+            request.headers()["Cookie"],
+            "origin=http://www.od.test/files/"
+        );
+        let user_agents: Vec<_> = request.headers().get_all(USER_AGENT).iter().collect();
+        assert_eq!(user_agents, vec!["Mozilla/5.0"]);
+
+        let request = build(&rules, "http://od.test.example/files/");
+        assert_eq!(request.method(), Method::HEAD);
+        assert_eq!(request.headers()[USER_AGENT], "odcrawler");
+        assert!(request.headers().get("Cookie").is_none());
+    }
+
+    #[test]
+    fn only_the_host_is_rewritten() {
+        let rules = HostRules::new(vec![HostRule {
+            host: "^ftp$".to_string(),
+            rewrite_host: Some("mirror.test".to_string()),
+            ..Default::default()
+        }])
+        .unwrap();
+
+        let request = build(&rules, "ftp://ftp/pub/ftp/");
+        assert_eq!(request.uri(), "ftp://mirror.test/pub/ftp/");
+    }
+
+    #[test]
+    fn invalid_methods_are_rejected() {
+        let rule = HostRule {
+            host: "od\\.test".to_string(),
+            method: Some("NOT A METHOD".to_string()),
+            ..Default::default()
+        };
+        assert!(HostRules::new(vec![rule]).is_err());
+    }
+}
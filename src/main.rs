@@ -3,25 +3,38 @@ extern crate log;
 #[macro_use]
 extern crate async_trait;
 
-use crate::elastic::ElasticLink;
-use anyhow::Result;
-use futures::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use crate::host_rules::HostRules;
+use crate::proxy::ProxyPool;
+use crate::soft404::PageSignatures;
+use anyhow::{bail, Result};
 use shared::db;
 use shared::db::Database;
 use shrust::{Shell, ShellIO};
 use std::io::Write;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use structopt::StructOpt;
 use wither::bson::doc;
-use wither::Model;
 
 mod check_links;
+mod classify;
 mod elastic;
+mod export;
+mod ftp;
+mod host_rules;
+mod politeness;
+mod proxy;
+mod reconcile;
 mod scans;
+mod search;
+mod soft404;
 mod stats;
+#[cfg(feature = "tantivy")]
+mod tantivy_index;
+#[cfg(test)]
+mod testing;
+mod tls;
 
 macro_rules! enclose {
     ( ($( $x:ident ),*) $y:expr ) => {
@@ -45,21 +58,123 @@ pub struct Opt {
     #[structopt(long)]
     scan_dir: Vec<PathBuf>,
 
-    /// Elasticsearch address
-    #[structopt(long, default_value = "http://127.0.0.1:9200")]
-    elastic_url: String,
+    #[structopt(flatten)]
+    elastic: elastic::Config,
 
-    /// Elasticsearch password
-    #[structopt(long, env = "ELASTIC_PASS", default_value = "")]
-    elastic_pass: String,
+    /// Where links are made searchable
+    #[structopt(long, default_value = "elastic", possible_values = &["elastic", "tantivy"])]
+    search_backend: String,
+
+    /// Directory of the embedded search index, if the tantivy backend is used
+    #[structopt(long, default_value = "search-index")]
+    #[cfg_attr(not(feature = "tantivy"), allow(dead_code))]
+    tantivy_dir: PathBuf,
+
+    #[structopt(skip)]
+    search: search::Search,
+
+    /// Number of versioned link indices to keep after an export, including the live one
+    #[structopt(long, default_value = "2")]
+    index_retention: usize,
 
     /// Directory for public files (e.g. stats.json)
     #[structopt(long, default_value = ".")]
     public_dir: PathBuf,
 
+    /// JSON file with host-specific request rules
+    #[structopt(long, default_value = "host_rules.json")]
+    host_rules: PathBuf,
+
+    #[structopt(skip)]
+    rules: HostRules,
+
+    /// JSON file with patterns recognizing parked domains, login walls and error pages
+    #[structopt(long, default_value = "page_signatures.json")]
+    page_signatures: PathBuf,
+
+    #[structopt(skip)]
+    signatures: PageSignatures,
+
+    /// How many redirects to follow when checking an OD
+    #[structopt(long, default_value = "5")]
+    max_redirects: u32,
+
+    /// How many random files of each OD to check alongside its root
+    #[structopt(long, default_value = "5")]
+    file_samples: i64,
+
+    /// How many ODs to check at once
+    #[structopt(long, default_value = "128")]
+    check_concurrency: usize,
+
+    /// How many requests to make to a single host at once
+    #[structopt(long, default_value = "4")]
+    host_concurrency: usize,
+
+    /// Minimum delay between two requests to the same host, in milliseconds
+    #[structopt(long, default_value = "0")]
+    host_delay: u64,
+
+    /// Timeout for checking an OD, in seconds
+    #[structopt(long, default_value = "20")]
+    check_timeout: u64,
+
+    /// User agent for checking ODs
+    #[structopt(long)]
+    user_agent: Option<String>,
+
+    /// Require TLS for ftp:// ODs (explicit FTPS with AUTH TLS). ftps:// always uses TLS.
+    #[structopt(long)]
+    ftps_explicit: bool,
+
+    /// HTTP or SOCKS5 proxies to send checks through, e.g. socks5h://127.0.0.1:1080
+    #[structopt(long)]
+    proxy: Vec<String>,
+
+    #[structopt(skip)]
+    proxies: Arc<ProxyPool>,
+
+    /// Number of failed checks after which an OD counts as dead. It's saved in the database, so
+    /// it only has to be given to change it, which re-evaluates all ODs. Defaults to 10.
+    #[structopt(long, parse(try_from_str = parse_threshold))]
+    dead_od_threshold: Option<i32>,
+
+    /// Only report differences between the database and the search index when reconciling
+    /// on schedule, instead of fixing them
+    #[structopt(long)]
+    reconcile_report_only: bool,
+
     // Disables the scheduler, allowing for exports etc.
     #[structopt(long)]
     disable_scheduler: bool,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Checks a single OD, prints the outcome and exits
+    Check {
+        url: String,
+        /// Save the result like a scheduled check, adding/removing links to/from Elasticsearch
+        #[structopt(long)]
+        persist: bool,
+    },
+    /// Exports links to the search index and exits. Indices created by older versions are
+    /// replaced by a full export on startup first
+    Export {
+        #[structopt(flatten)]
+        scope: export::ExportScope,
+    },
+}
+
+/// Parses a dead OD threshold, which has to be at least 1.
+fn parse_threshold(s: &str) -> Result<i32> {
+    match s.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("Invalid threshold '{}', expected a positive number", s),
+    }
 }
 
 #[async_std::main]
@@ -84,9 +199,44 @@ async fn main() -> Result<()> {
     odd_scan_dir.push("Scans");
     std::fs::create_dir_all(&odd_scan_dir).unwrap();
     opt.scan_dir.push(odd_scan_dir);
+    opt.rules = HostRules::load(&opt.host_rules)?;
+    opt.signatures = PageSignatures::load(&opt.page_signatures)?;
+    opt.proxies = Arc::new(ProxyPool::new(&opt.proxy)?);
+    opt.search = search::open(&opt).await?;
     dbg!(&opt);
 
-    let db = db::Database::new().await.unwrap();
+    let mut db = db::Database::new().await.unwrap();
+    if let Some(threshold) = opt.dead_od_threshold {
+        let previous_threshold = db.set_dead_od_threshold(threshold).await?;
+        if previous_threshold != threshold {
+            check_links::reevaluate_opendirectories(&opt, &db, previous_threshold).await?;
+        }
+    }
+
+    // Indices created by older versions lack fields (e.g. the OD of links) or reject new ones,
+    // so upgrading requires a full export. It's done here before the scheduler or a scoped
+    // export, but not for checking a single OD, which shouldn't wait hours for it.
+    let rebuild = match &opt.command {
+        None => true,
+        Some(Command::Export { scope }) => !scope.is_full(),
+        Some(Command::Check { .. }) => false,
+    };
+    if rebuild && opt.search.needs_rebuild().await? {
+        warn!("The search index is outdated, starting a full export before anything else");
+        export::export(&opt, &db, &export::ExportScope::default()).await?;
+    }
+
+    match &opt.command {
+        Some(Command::Check { url, persist }) => {
+            print!(
+                "{}",
+                check_links::check_single(&opt, &db, url, *persist).await?
+            );
+            return Ok(());
+        }
+        Some(Command::Export { scope }) => return export::export(&opt, &db, scope).await,
+        None => {}
+    }
 
     if !opt.disable_scheduler {
         let scheduler_db = db.clone();
@@ -102,6 +252,68 @@ async fn main() -> Result<()> {
         warn!("STUB: add {} to DB", s[0]);
         Ok(())
     });
+    shell.new_command(
+        "check",
+        "Checks a single OD and prints the outcome: check <url> [persist]",
+        1,
+        enclose! { (opt, db) move |io, _, s| {
+            let persist = s.get(1) == Some(&"persist");
+            let result = check_links::check_single(&opt, &db, s[0], persist);
+            match async_std::task::block_on(result) {
+                Ok(report) => write!(io, "{}", report)?,
+                Err(e) => {
+                    writeln!(io, "Error while checking OD: {}", e)?;
+                    error!("Error while checking OD: {}", e);
+                }
+            };
+            Ok(())
+        }},
+    );
+    shell.new_command(
+        "pin",
+        "Pins an OD as alive or dead regardless of checks: pin <url> <alive|dead|none>",
+        2,
+        enclose! { (opt, db) move |io, _, s| {
+            let pinned = match s[1] {
+                "alive" => Some(true),
+                "dead" => Some(false),
+                "none" => None,
+                other => {
+                    writeln!(io, "Unknown pin '{}', expected alive, dead or none", other)?;
+                    return Ok(());
+                }
+            };
+            let result = check_links::pin_opendirectory(&opt, &db, s[0], pinned);
+            if let Err(e) = async_std::task::block_on(result) {
+                writeln!(io, "Error while pinning OD: {}", e)?;
+                error!("Error while pinning OD: {}", e);
+            };
+            Ok(())
+        }},
+    );
+    shell.new_command(
+        "threshold",
+        "Sets the dead OD threshold for a single OD: threshold <url> <number|none>",
+        2,
+        enclose! { (opt, db) move |io, _, s| {
+            let threshold = match s[1] {
+                "none" => None,
+                n => match parse_threshold(n) {
+                    Ok(n) => Some(n),
+                    Err(e) => {
+                        writeln!(io, "{}", e)?;
+                        return Ok(());
+                    }
+                },
+            };
+            let result = check_links::set_opendirectory_threshold(&opt, &db, s[0], threshold);
+            if let Err(e) = async_std::task::block_on(result) {
+                writeln!(io, "Error while setting threshold: {}", e)?;
+                error!("Error while setting threshold: {}", e);
+            };
+            Ok(())
+        }},
+    );
     shell.new_command_noargs(
         "dump",
         "Creates a new Dump",
@@ -113,72 +325,43 @@ async fn main() -> Result<()> {
             Ok(())
         }},
     );
-    shell.new_command_noargs(
+    shell.new_command(
         "export",
-        "Exports all links to Elasticsearch",
-        enclose! { (db, opt) move |io, _| {
-            if let Err(e) = async_std::task::block_on(export_all(&opt, &db)) {
+        "Exports links to the search index: export [--od <url>] [--since <YYYY-MM-DD>]",
+        0,
+        enclose! { (db, opt) move |io, _, s| {
+            let args = std::iter::once("export").chain(s.iter().copied());
+            let scope = match export::ExportScope::from_iter_safe(args) {
+                Ok(scope) => scope,
+                Err(e) => {
+                    writeln!(io, "{}", e.message)?;
+                    return Ok(());
+                }
+            };
+            if let Err(e) = async_std::task::block_on(export::export(&opt, &db, &scope)) {
                 writeln!(io, "Error while exporting links: {}", e)?;
                 error!("Error while exporting links: {}", e);
             };
             Ok(())
         }},
     );
-    shell.run_loop(&mut ShellIO::default());
-
-    Ok(())
-}
-
-pub async fn export_all(opt: &Opt, db: &Database) -> Result<()> {
-    info!("Adding or removing all links to/from Elasticsearch");
-
-    let alive_ods: Vec<String> = db
-        .get_opendirectories(false)
-        .await?
-        .filter_map(|r| async { r.ok().map(|od| od.url) })
-        .collect()
-        .await;
-
-    let total = db::Link::collection(&db.db)
-        .estimated_document_count(None)
-        .await? as u64;
-    let exported = AtomicU64::new(0);
-    let pb = ProgressBar::new(total).with_style(
-        ProgressStyle::default_bar().template("{percent}%, ETA {eta}   {wide_bar}   {pos}/~{len}"),
-    );
-    pb.enable_steady_tick(100);
-
-    db::Link::find(&db.db, doc! {}, None)
-        .await?
-        .filter_map(|l| async { l.ok() })
-        .map(|l| (alive_ods.contains(&l.opendirectory), ElasticLink::from(l)))
-        .chunks(5_000)
-        .for_each_concurrent(4, |chunk| async {
-            let len = chunk.len();
-            let chunk = chunk;
-            let mut body = elastic::BulkBody::default();
-            for (is_alive, link) in chunk {
-                body.items.push(if is_alive {
-                    elastic::BulkAction::Index(link)
-                } else {
-                    elastic::BulkAction::Delete(link.id)
-                });
-            }
-            if let Err(e) = elastic::bulk_request(opt, body) {
-                error!("Error exporting links to Elasticsearch: {}", e);
+    shell.new_command(
+        "reconcile",
+        "Compares the search index with the database: reconcile [fix]",
+        0,
+        enclose! { (opt, db) move |io, _, s| {
+            let fix = s.first() == Some(&"fix");
+            match async_std::task::block_on(reconcile::reconcile(&db, &*opt.search, fix)) {
+                Ok(report) => writeln!(io, "{}", report)?,
+                Err(e) => {
+                    writeln!(io, "Error while reconciling: {}", e)?;
+                    error!("Error while reconciling: {}", e);
+                }
             };
-
-            exported.fetch_add(len as u64, Ordering::Relaxed);
-
-            let exported_inner = exported.load(Ordering::Relaxed);
-            if exported_inner > total {
-                pb.set_length(total);
-            }
-            pb.set_position(exported_inner as u64);
-        })
-        .await;
-
-    info!("Exported {} documents", exported.into_inner());
+            Ok(())
+        }},
+    );
+    shell.run_loop(&mut ShellIO::default());
 
     Ok(())
 }
@@ -273,15 +456,51 @@ impl Schedule for CreateDump {
     }
 }
 
+struct ClassifyLinks;
+#[async_trait]
+impl Schedule for ClassifyLinks {
+    fn name(&self) -> &str {
+        "classify links"
+    }
+
+    fn frequency(&self) -> u16 {
+        10
+    }
+
+    async fn run(&self, _opt: &Opt, db: &mut Database) -> Result<()> {
+        classify::classify_links(db).await?;
+        Ok(())
+    }
+}
+
+struct Reconcile;
+#[async_trait]
+impl Schedule for Reconcile {
+    fn name(&self) -> &str {
+        "reconcile search index"
+    }
+
+    fn frequency(&self) -> u16 {
+        500
+    }
+
+    async fn run(&self, opt: &Opt, db: &mut Database) -> Result<()> {
+        reconcile::reconcile(db, &*opt.search, !opt.reconcile_report_only).await?;
+        Ok(())
+    }
+}
+
 async fn scheduler_loop(opt: Opt, mut db: Database) {
     info!("Started scheduler thread");
 
-    let schedule_tasks: [Box<dyn Schedule>; 5] = [
+    let schedule_tasks: [Box<dyn Schedule>; 7] = [
         Box::new(ProcessResults),
         Box::new(ScanOpendirectory),
         Box::new(CheckLinks),
         Box::new(UpdateStats),
         Box::new(CreateDump),
+        Box::new(ClassifyLinks),
+        Box::new(Reconcile),
     ];
 
     let mut counter: u16 = 1;
@@ -299,3 +518,19 @@ async fn scheduler_loop(opt: Opt, mut db: Database) {
         counter = counter.overflowing_add(1).0;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threshold_is_optional_and_positive() {
+        assert_eq!(testing::opt().dead_od_threshold, None);
+        let opt = Opt::from_iter(&["odcrawler_discovery", "--dead-od-threshold", "3"]);
+        assert_eq!(opt.dead_od_threshold, Some(3));
+        for invalid in &["0", "-1", "x"] {
+            let args = ["odcrawler_discovery", "--dead-od-threshold", invalid];
+            assert!(Opt::from_iter_safe(&args).is_err());
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use crate::Opt;
+use async_lock::{Semaphore, SemaphoreGuardArc};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct HostSlot {
+    semaphore: Arc<Semaphore>,
+    delay: Duration,
+    next_request: Mutex<Instant>,
+}
+
+/// Limits how many requests are made to a single host at once, and how quickly.
+pub struct HostLimiter<'a> {
+    opt: &'a Opt,
+    hosts: Mutex<HashMap<String, Arc<HostSlot>>>,
+}
+
+impl<'a> HostLimiter<'a> {
+    pub fn new(opt: &'a Opt) -> Self {
+        Self {
+            opt,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until a request to the host of `link` is allowed.
+    /// The returned guard has to be held until the request is done.
+    pub async fn acquire(&self, link: &str) -> SemaphoreGuardArc {
+        let slot = self.slot(link);
+        let guard = slot.semaphore.acquire_arc().await;
+
+        let wait = {
+            let mut next_request = slot.next_request.lock().unwrap();
+            let now = Instant::now();
+            let start = (*next_request).max(now);
+            *next_request = start + slot.delay;
+            start - now
+        };
+        if wait > Duration::from_millis(0) {
+            async_std::task::sleep(wait).await;
+        }
+
+        guard
+    }
+
+    fn slot(&self, link: &str) -> Arc<HostSlot> {
+        let host = shared::host_of(link).unwrap_or_else(|| link.to_string());
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .entry(host)
+            .or_insert_with(|| {
+                let rule = self.opt.rules.find(link).map(|(_, rule)| rule);
+                let concurrency = rule
+                    .and_then(|r| r.concurrency)
+                    .unwrap_or(self.opt.host_concurrency);
+                let delay = rule.and_then(|r| r.delay).unwrap_or(self.opt.host_delay);
+                Arc::new(HostSlot {
+                    semaphore: Arc::new(Semaphore::new(concurrency.max(1))),
+                    delay: Duration::from_millis(delay),
+                    next_request: Mutex::new(Instant::now()),
+                })
+            })
+            .clone()
+    }
+}
+
+/// Reorders links so that consecutive ones are on different hosts where possible.
+/// This keeps a host with many ODs from occupying all global slots while it's throttled.
+pub fn interleave_by_host<T>(items: Vec<T>, link: impl Fn(&T) -> &str) -> Vec<T> {
+    let mut queues: Vec<VecDeque<T>> = vec![];
+    let mut by_host: HashMap<String, usize> = HashMap::new();
+    let total = items.len();
+    for item in items {
+        let host = shared::host_of(link(&item)).unwrap_or_default();
+        let queue = *by_host.entry(host).or_insert_with(|| {
+            queues.push(VecDeque::new());
+            queues.len() - 1
+        });
+        queues[queue].push_back(item);
+    }
+
+    // Hosts take turns, and drop out once they have nothing left
+    let mut turns: VecDeque<VecDeque<T>> = queues.into();
+    let mut interleaved = Vec::with_capacity(total);
+    while let Some(mut queue) = turns.pop_front() {
+        if let Some(item) = queue.pop_front() {
+            interleaved.push(item);
+            turns.push_back(queue);
+        }
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_take_turns() {
+        let links = vec![
+            "http://a.test/1/",
+            "http://a.test/2/",
+            "http://a.test/3/",
+            "http://b.test/1/",
+            "http://c.test/1/",
+            "http://c.test/2/",
+        ];
+        assert_eq!(
+            interleave_by_host(links, |l| *l),
+            vec![
+                "http://a.test/1/",
+                "http://b.test/1/",
+                "http://c.test/1/",
+                "http://a.test/2/",
+                "http://c.test/2/",
+                "http://a.test/3/",
+            ]
+        );
+        assert!(interleave_by_host(Vec::<&str>::new(), |l| *l).is_empty());
+    }
+}
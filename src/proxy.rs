@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use isahc::http::Uri;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Consecutive failures after which a proxy is taken out of rotation
+const MAX_FAILURES: u32 = 5;
+/// How long an unhealthy proxy is taken out of rotation
+const COOLDOWN: Duration = Duration::from_secs(300);
+/// How long connecting to a proxy may take when checking whether it's at fault
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// curl's port for proxies without one, whatever their type
+const DEFAULT_PORT: u16 = 1080;
+
+/// How a request through a proxy failed, as far as the proxy is concerned.
+pub enum Failure {
+    /// The proxy's host couldn't be resolved
+    ProxyUnresolved,
+    /// Either the proxy or the target couldn't be connected to. curl doesn't tell which when
+    /// a SOCKS5 proxy couldn't reach the target.
+    ConnectFailed,
+    /// Anything else, which the target is to blame for
+    Other,
+}
+
+impl From<&isahc::Error> for Failure {
+    fn from(error: &isahc::Error) -> Self {
+        match error {
+            isahc::Error::CouldntResolveProxy => Failure::ProxyUnresolved,
+            isahc::Error::ConnectFailed => Failure::ConnectFailed,
+            _ => Failure::Other,
+        }
+    }
+}
+
+impl From<&curl::Error> for Failure {
+    fn from(error: &curl::Error) -> Self {
+        if error.is_couldnt_resolve_proxy() {
+            Failure::ProxyUnresolved
+        } else if error.is_couldnt_connect() {
+            Failure::ConnectFailed
+        } else {
+            Failure::Other
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Proxy {
+    uri: Uri,
+    failures: AtomicU32,
+    disabled_until: Mutex<Option<Instant>>,
+}
+
+impl Proxy {
+    fn is_healthy(&self) -> bool {
+        match *self.disabled_until.lock().unwrap() {
+            Some(until) => until <= Instant::now(),
+            None => true,
+        }
+    }
+}
+
+/// A set of HTTP or SOCKS proxies (e.g. `http://host:8080`, `socks5h://host:1080`).
+///
+/// Each host sticks to one proxy as long as that proxy is healthy, so a site sees a consistent IP.
+#[derive(Debug, Default)]
+pub struct ProxyPool {
+    proxies: Vec<Proxy>,
+    assignments: Mutex<HashMap<String, usize>>,
+    next: AtomicUsize,
+}
+
+impl ProxyPool {
+    pub fn new(uris: &[String]) -> Result<Self> {
+        let mut proxies = vec![];
+        for uri in uris {
+            proxies.push(Proxy {
+                uri: uri
+                    .parse()
+                    .with_context(|| format!("Invalid proxy URI '{}'", uri))?,
+                failures: AtomicU32::new(0),
+                disabled_until: Mutex::new(None),
+            });
+        }
+        Ok(Self {
+            proxies,
+            ..Default::default()
+        })
+    }
+
+    /// Picks the proxy to use for `link`. Returns `None` if no proxies are configured.
+    pub fn for_link(&self, link: &str) -> Option<(usize, Uri)> {
+        if self.proxies.is_empty() {
+            return None;
+        }
+
+        let host = shared::host_of(link).unwrap_or_default();
+        let mut assignments = self.assignments.lock().unwrap();
+        let index = match assignments.get(&host) {
+            Some(&i) if self.proxies[i].is_healthy() => i,
+            _ => {
+                let i = self.next_healthy();
+                assignments.insert(host, i);
+                i
+            }
+        };
+        Some((index, self.proxies[index].uri.clone()))
+    }
+
+    /// Records the outcome of a request made through the proxy at `index`.
+    pub fn report(&self, index: usize, proxy_ok: bool) {
+        let proxy = &self.proxies[index];
+        if proxy_ok {
+            proxy.failures.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = proxy.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= MAX_FAILURES {
+            warn!(
+                "Proxy {} failed {} times in a row, disabling it for {}s",
+                proxy.uri,
+                failures,
+                COOLDOWN.as_secs()
+            );
+            proxy.failures.store(0, Ordering::Relaxed);
+            *proxy.disabled_until.lock().unwrap() = Some(Instant::now() + COOLDOWN);
+        }
+    }
+
+    /// Records the outcome of a request made through the proxy at `index`, given how it failed
+    /// (if it did). Failed connections only count against the proxy if it can't be connected to
+    /// itself, since dead ODs behind a SOCKS5 proxy fail the same way.
+    pub async fn report_result(&self, index: usize, failure: Option<Failure>) {
+        let proxy_ok = match failure {
+            None | Some(Failure::Other) => true,
+            Some(Failure::ProxyUnresolved) => false,
+            Some(Failure::ConnectFailed) => self.accepts_connections(index).await,
+        };
+        self.report(index, proxy_ok);
+    }
+
+    /// Whether a TCP connection to the proxy at `index` can be made.
+    async fn accepts_connections(&self, index: usize) -> bool {
+        let uri = &self.proxies[index].uri;
+        let host = match uri.host() {
+            // IPv6 addresses are enclosed in brackets
+            Some(host) => host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_string(),
+            None => return false,
+        };
+        let port = uri.port_u16().unwrap_or(DEFAULT_PORT);
+        let connect = async_std::net::TcpStream::connect((host.as_str(), port));
+        matches!(
+            async_std::future::timeout(PROBE_TIMEOUT, connect).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Round-robins over healthy proxies. If none are healthy, the one that recovers first is used.
+    fn next_healthy(&self) -> usize {
+        let len = self.proxies.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|offset| (start + offset) % len)
+            .find(|&i| self.proxies[i].is_healthy())
+            .unwrap_or_else(|| {
+                (0..len)
+                    .min_by_key(|&i| *self.proxies[i].disabled_until.lock().unwrap())
+                    .unwrap()
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_links::link_is_reachable;
+    use crate::testing;
+    use std::sync::Arc;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn ok(_: &testing::Request) -> String {
+        testing::response("200 OK", "")
+    }
+
+    #[async_std::test]
+    async fn checks_go_through_http_proxy() {
+        let (proxy, tunneled) = testing::http_proxy(testing::https_server(ok));
+        let mut opt = testing::opt();
+        opt.proxies = Arc::new(ProxyPool::new(&[format!("http://{}", proxy)]).unwrap());
+
+        let link = format!("https://{}/", testing::HOST);
+        assert!(link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        assert_eq!(tunneled.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn checks_go_through_socks5_proxy() {
+        let (proxy, tunneled) = testing::socks5_proxy(testing::http_server(ok));
+        let mut opt = testing::opt();
+        opt.proxies = Arc::new(ProxyPool::new(&[format!("socks5h://{}", proxy)]).unwrap());
+
+        let link = format!("http://{}/", testing::HOST);
+        assert!(link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        assert_eq!(tunneled.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn dead_targets_leave_the_proxy_healthy() {
+        let (proxy, tunneled) = testing::socks5_proxy(testing::closed_port());
+        let mut opt = testing::opt();
+        opt.proxies = Arc::new(ProxyPool::new(&[format!("socks5h://{}", proxy)]).unwrap());
+
+        let link = format!("http://{}/", testing::HOST);
+        for _ in 0..MAX_FAILURES {
+            assert!(!link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        }
+        assert!(opt.proxies.proxies[0].is_healthy());
+        assert_eq!(opt.proxies.proxies[0].failures.load(Ordering::SeqCst), 0);
+        assert_eq!(tunneled.load(Ordering::SeqCst), 0);
+    }
+
+    #[async_std::test]
+    async fn dead_proxy_is_skipped() {
+        let (proxy, tunneled) = testing::socks5_proxy(testing::http_server(ok));
+        let mut opt = testing::opt();
+        opt.proxies = Arc::new(
+            ProxyPool::new(&[
+                format!("socks5h://{}", testing::closed_port()),
+                format!("socks5h://{}", proxy),
+            ])
+            .unwrap(),
+        );
+
+        let link = format!("http://{}/", testing::HOST);
+        for _ in 0..MAX_FAILURES {
+            assert!(!link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        }
+        assert!(!opt.proxies.proxies[0].is_healthy());
+        assert!(link_is_reachable(&opt, &link, TIMEOUT, false).await);
+        assert_eq!(tunneled.load(Ordering::SeqCst), 1);
+        assert_eq!(opt.proxies.for_link(&link).unwrap().0, 1);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::elastic::ElasticLink;
+use crate::search::SearchIndex;
+use anyhow::Result;
+use futures::{Future, Stream, StreamExt};
+use shared::db::Database;
+use std::cmp::Ordering;
+use std::fmt;
+use wither::bson::doc;
+
+/// How many differences are collected before they're fixed
+const BATCH_SIZE: usize = 5_000;
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Searchable links in the database
+    pub links: u64,
+    /// Documents in the search index
+    pub documents: u64,
+    /// Searchable links which weren't in the search index
+    pub missing: u64,
+    /// Documents which shouldn't be in the search index
+    pub stale: u64,
+    /// Missing links which couldn't be added while fixing
+    pub failed_to_add: u64,
+    /// Stale documents which couldn't be removed while fixing
+    pub failed_to_remove: u64,
+    /// Whether the differences were fixed
+    pub fixed: bool,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} searchable links, {} documents in the search index, {} missing, {} stale",
+            self.links, self.documents, self.missing, self.stale
+        )?;
+        if self.fixed {
+            write!(f, " (fixed)")?;
+        }
+        if self.failed_to_add + self.failed_to_remove > 0 {
+            write!(
+                f,
+                ", {} couldn't be added and {} couldn't be removed",
+                self.failed_to_add, self.failed_to_remove
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the searchable links in the database with a search index.
+///
+/// Both are streamed in ID order, so only the differences are kept in memory. If `fix` is set,
+/// missing links are added and stale documents removed; otherwise nothing is changed.
+pub async fn reconcile(db: &Database, index: &dyn SearchIndex, fix: bool) -> Result<Report> {
+    info!("Reconciling {} with the database", index.name());
+    let alive_ods = db.alive_opendirectory_urls().await?;
+    let alive_ods = &alive_ods;
+    let links = db.get_searchable_links(doc! {}).await?.map(|l| {
+        let id = l?.id.map(|id| id.to_string());
+        Ok::<_, anyhow::Error>(id.unwrap_or_default())
+    });
+    let lookup = move |ids: Vec<String>| async move {
+        let documents: Vec<ElasticLink> = db
+            .get_links_by_ids(&ids)
+            .await?
+            .into_iter()
+            .filter_map(|l| {
+                let od_alive = alive_ods.contains(&l.opendirectory);
+                ElasticLink::new(l, od_alive)
+                    .map_err(|e| warn!("Skipping link: {}", e))
+                    .ok()
+            })
+            .collect();
+        Ok::<_, anyhow::Error>(documents)
+    };
+
+    let report = diff(links, index.ids(), index, fix, lookup).await?;
+    info!("{}", report);
+    Ok(report)
+}
+
+/// Merge-joins the IDs of searchable links with the IDs of documents in `index`, both in
+/// ascending order. If `fix` is set, missing links are added in batches, with their documents
+/// built by `lookup`, and stale documents are removed.
+async fn diff<F, Fut>(
+    links: impl Stream<Item = Result<String>>,
+    documents: impl Stream<Item = Result<String>>,
+    index: &dyn SearchIndex,
+    fix: bool,
+    lookup: F,
+) -> Result<Report>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<ElasticLink>>>,
+{
+    futures::pin_mut!(links);
+    futures::pin_mut!(documents);
+    let mut report = Report {
+        fixed: fix,
+        ..Default::default()
+    };
+    let mut to_add = vec![];
+    let mut to_remove = vec![];
+
+    let mut link = links.next().await.transpose()?;
+    let mut document = documents.next().await.transpose()?;
+    loop {
+        // Whichever side is behind has an ID the other one lacks
+        let order = match (&link, &document) {
+            (None, None) => break,
+            (Some(link), Some(document)) => link.cmp(document),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+        };
+        match order {
+            Ordering::Equal => {
+                report.links += 1;
+                report.documents += 1;
+                link = links.next().await.transpose()?;
+                document = documents.next().await.transpose()?;
+            }
+            Ordering::Less => {
+                report.links += 1;
+                report.missing += 1;
+                if fix {
+                    to_add.extend(link.take());
+                }
+                link = links.next().await.transpose()?;
+            }
+            Ordering::Greater => {
+                report.documents += 1;
+                report.stale += 1;
+                if fix {
+                    to_remove.extend(document.take());
+                }
+                document = documents.next().await.transpose()?;
+            }
+        }
+
+        if to_add.len() >= BATCH_SIZE {
+            report.failed_to_add += add(index, &lookup, std::mem::take(&mut to_add)).await?;
+        }
+        if to_remove.len() >= BATCH_SIZE {
+            report.failed_to_remove += index.delete(&to_remove).await? as u64;
+            to_remove.clear();
+        }
+    }
+
+    if !to_add.is_empty() {
+        report.failed_to_add += add(index, &lookup, to_add).await?;
+    }
+    if !to_remove.is_empty() {
+        report.failed_to_remove += index.delete(&to_remove).await? as u64;
+    }
+    Ok(report)
+}
+
+/// Adds the links with these IDs. Returns how many couldn't be added, including those `lookup`
+/// found no document for.
+async fn add<F, Fut>(index: &dyn SearchIndex, lookup: &F, ids: Vec<String>) -> Result<u64>
+where
+    F: Fn(Vec<String>) -> Fut,
+    Fut: Future<Output = Result<Vec<ElasticLink>>>,
+{
+    let requested = ids.len();
+    let documents = lookup(ids).await?;
+    let failed = index.index(&documents).await?;
+    Ok((requested.saturating_sub(documents.len()) + failed) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::Recorder;
+
+    fn ids(ids: &[&str]) -> impl Stream<Item = Result<String>> {
+        let ids: Vec<Result<String>> = ids.iter().map(|id| Ok(id.to_string())).collect();
+        futures::stream::iter(ids)
+    }
+
+    fn document(id: String) -> ElasticLink {
+        ElasticLink {
+            id,
+            url: String::new(),
+            filename: String::new(),
+            extension: None,
+            opendirectory: String::new(),
+            host: None,
+            tld: None,
+            segments: vec![],
+            parent: None,
+            size: None,
+            last_modified: None,
+            category: None,
+            alive: true,
+        }
+    }
+
+    async fn lookup(ids: Vec<String>) -> Result<Vec<ElasticLink>> {
+        Ok(ids.into_iter().map(document).collect())
+    }
+
+    async fn run(links: &[&str], documents: &[&str], fix: bool) -> (Report, Recorder) {
+        let index = Recorder::default();
+        let report = diff(ids(links), ids(documents), &index, fix, lookup)
+            .await
+            .unwrap();
+        (report, index)
+    }
+
+    #[async_std::test]
+    async fn interleaved_differences_are_fixed() {
+        let links = ["1", "3", "4", "6"];
+        let documents = ["2", "3", "5", "6", "7"];
+        let (report, index) = run(&links, &documents, true).await;
+
+        assert_eq!(
+            (report.links, report.documents, report.missing, report.stale),
+            (4, 5, 2, 3)
+        );
+        assert!(report.fixed);
+        assert_eq!(*index.indexed.lock().unwrap(), vec![vec!["1", "4"]]);
+        assert_eq!(*index.deleted.lock().unwrap(), vec!["2", "5", "7"]);
+    }
+
+    #[async_std::test]
+    async fn either_side_may_be_empty() {
+        let (report, index) = run(&[], &["1", "2"], true).await;
+        assert_eq!((report.links, report.stale), (0, 2));
+        assert_eq!(*index.deleted.lock().unwrap(), vec!["1", "2"]);
+
+        let (report, index) = run(&["1"], &[], true).await;
+        assert_eq!((report.documents, report.missing), (0, 1));
+        assert_eq!(*index.indexed.lock().unwrap(), vec![vec!["1"]]);
+
+        let (report, index) = run(&[], &[], true).await;
+        assert_eq!((report.links, report.documents), (0, 0));
+        assert!(index.indexed.lock().unwrap().is_empty());
+        assert!(index.deleted.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn reports_change_nothing() {
+        let (report, index) = run(&["1", "3"], &["2", "3"], false).await;
+        assert_eq!((report.missing, report.stale), (1, 1));
+        assert!(!report.fixed);
+        assert!(index.indexed.lock().unwrap().is_empty());
+        assert!(index.deleted.lock().unwrap().is_empty());
+    }
+
+    #[async_std::test]
+    async fn missing_links_are_added_in_batches() {
+        let links: Vec<String> = (0..BATCH_SIZE + 1).map(|i| format!("{:05}", i)).collect();
+        let links: Vec<&str> = links.iter().map(String::as_str).collect();
+        let (report, index) = run(&links, &[], true).await;
+
+        assert_eq!(report.missing, BATCH_SIZE as u64 + 1);
+        let batches: Vec<usize> = index.indexed.lock().unwrap().iter().map(Vec::len).collect();
+        assert_eq!(batches, vec![BATCH_SIZE, 1]);
+    }
+
+    #[async_std::test]
+    async fn links_without_documents_count_as_failed() {
+        let index = Recorder::default();
+        let lookup = |ids: Vec<String>| async move {
+            let documents = ids.into_iter().filter(|id| id != "2").map(document);
+            Ok::<_, anyhow::Error>(documents.collect::<Vec<_>>())
+        };
+        let report = diff(ids(&["1", "2"]), ids(&[]), &index, true, lookup)
+            .await
+            .unwrap();
+
+        assert_eq!((report.missing, report.failed_to_add), (2, 1));
+        assert_eq!(*index.indexed.lock().unwrap(), vec![vec!["1"]]);
+    }
+}
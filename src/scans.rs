@@ -1,13 +1,15 @@
-use crate::elastic;
+use crate::search;
 use crate::Opt;
 use anyhow::bail;
 use anyhow::Result;
+use chrono::Datelike;
 use flate2::read::GzDecoder;
 use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use shared::db::{Database, SaveResult};
+use shared::db::{Database, Link, SaveResult};
 use std::io::BufReader;
 use std::time::Duration;
+use wither::bson::DateTime;
 
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
@@ -19,7 +21,8 @@ pub struct OdScanResult {
 #[serde(rename_all = "PascalCase")]
 pub struct OdScanDirectory {
     pub url: String,
-    pub subdirectories: Vec<OdScanDirectory>,
+    // Can be null in FTP scans
+    pub subdirectories: Option<Vec<OdScanDirectory>>,
     pub files: Option<Vec<OdScanFile>>,
 }
 
@@ -27,6 +30,26 @@ pub struct OdScanDirectory {
 #[serde(rename_all = "PascalCase")]
 pub struct OdScanFile {
     pub url: String,
+    // Negative if unknown
+    #[serde(default)]
+    pub file_size: Option<i64>,
+    #[serde(default)]
+    pub last_modified: Option<String>,
+}
+
+/// ODD writes local times without an offset, and `0001-01-01T00:00:00` if the date is unknown.
+fn parse_last_modified(date: &str) -> Option<DateTime> {
+    let parsed = match chrono::DateTime::parse_from_rfc3339(date) {
+        Ok(d) => d.with_timezone(&chrono::Utc),
+        Err(_) => {
+            let naive = chrono::NaiveDateTime::parse_from_str(date, "%Y-%m-%dT%H:%M:%S%.f").ok()?;
+            chrono::DateTime::from_utc(naive, chrono::Utc)
+        }
+    };
+    if parsed.year() <= 1 {
+        return None;
+    }
+    Some(parsed.into())
 }
 
 pub async fn process_scans(opt: &Opt, db: &mut Database) -> Result<()> {
@@ -79,20 +102,13 @@ pub async fn process_scans(opt: &Opt, db: &mut Database) -> Result<()> {
     info!("Found {} files", files.len());
 
     let is_reachable =
-        crate::check_links::link_is_reachable(&root_url, Duration::from_secs(30), true).await;
-    let links = files
-        .drain(..)
-        .map(|f| shared::db::Link {
-            id: None,
-            url: f.url,
-            opendirectory: root_url.clone(),
-        })
-        .collect();
+        crate::check_links::link_is_reachable(opt, &root_url, Duration::from_secs(30), true).await;
+    let links = scan_links(&root_url, files.drain(..));
     let save_result = db.save_scan_result(&root_url, links, is_reachable).await?;
     match save_result {
         SaveResult::Success => {
             if is_reachable {
-                elastic::add_links_from_db(opt, db, &root_url).await?;
+                search::add_links_from_db(opt, db, &root_url).await?;
             }
         }
         _ => {
@@ -111,6 +127,21 @@ pub async fn process_scans(opt: &Opt, db: &mut Database) -> Result<()> {
     Ok(())
 }
 
+/// Turns the files of a scan into links of the OD at `root_url`.
+fn scan_links(root_url: &str, files: impl Iterator<Item = OdScanFile>) -> Vec<Link> {
+    files
+        .map(|f| Link {
+            id: None,
+            category: crate::classify::classify(&f.url),
+            url: f.url,
+            opendirectory: root_url.to_string(),
+            missing: 0,
+            size: f.file_size.filter(|s| *s >= 0),
+            last_modified: f.last_modified.as_deref().and_then(parse_last_modified),
+        })
+        .collect()
+}
+
 fn collect_files(dir: OdScanDirectory) -> Vec<OdScanFile> {
     info!("Extracting files");
     collect_files_recursive(dir)
@@ -119,7 +150,7 @@ fn collect_files(dir: OdScanDirectory) -> Vec<OdScanFile> {
 fn collect_files_recursive(dir: OdScanDirectory) -> Vec<OdScanFile> {
     let mut files = dir.files.unwrap_or_default();
 
-    for subdir in dir.subdirectories {
+    for subdir in dir.subdirectories.unwrap_or_default() {
         files.extend(collect_files_recursive(subdir));
     }
 
@@ -130,3 +161,67 @@ pub fn scan_opendirectories() -> Result<()> {
     warn!("STUB: scan_opendirectories");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elastic::ElasticLink;
+    use shared::media::Category;
+    use wither::bson::oid::ObjectId;
+
+    // Trimmed from an ODD scan of an FTP server, which has null instead of empty lists
+    const FTP_SCAN: &str = r#"{
+        "Root": {
+            "Url": "ftp://ftp.example.org/pub/",
+            "Name": "pub",
+            "Subdirectories": [
+                {
+                    "Url": "ftp://ftp.example.org/pub/iso/",
+                    "Name": "iso",
+                    "Subdirectories": null,
+                    "Files": [
+                        {
+                            "Url": "ftp://ftp.example.org/pub/iso/debian-10.7.0-amd64-netinst.iso;type=i",
+                            "FileName": "debian-10.7.0-amd64-netinst.iso",
+                            "FileSize": 349175808,
+                            "LastModified": "2020-12-05T10:31:00"
+                        },
+                        {
+                            "Url": "ftp://ftp.example.org/pub/iso/SHA256SUMS",
+                            "FileName": "SHA256SUMS",
+                            "FileSize": -1,
+                            "LastModified": "0001-01-01T00:00:00"
+                        }
+                    ]
+                }
+            ],
+            "Files": null
+        }
+    }"#;
+
+    #[test]
+    fn ingests_ftp_scans() {
+        let scan: OdScanResult = serde_json::from_str(FTP_SCAN).unwrap();
+        let root_url = scan.root.url.clone();
+        let links = scan_links(&root_url, collect_files(scan.root).into_iter());
+
+        assert_eq!(links.len(), 2);
+        let mut links = links.into_iter();
+        let mut iso = links.next().unwrap();
+        assert_eq!(iso.opendirectory, "ftp://ftp.example.org/pub/");
+        assert_eq!(iso.size, Some(349_175_808));
+        assert!(iso.last_modified.is_some());
+        assert_eq!(iso.category, Some(Category::DiskImage));
+        let sums = links.next().unwrap();
+        assert_eq!(sums.size, None);
+        assert_eq!(sums.last_modified, None);
+        assert_eq!(sums.category, None);
+
+        iso.id = Some(ObjectId::new());
+        let document = ElasticLink::new(iso, true).unwrap();
+        assert_eq!(document.filename, "debian-10.7.0-amd64-netinst.iso");
+        assert_eq!(document.extension.as_deref(), Some("iso"));
+        assert_eq!(document.host.as_deref(), Some("ftp.example.org"));
+        assert_eq!(document.segments, vec!["pub", "iso"]);
+    }
+}
@@ -0,0 +1,153 @@
+use crate::elastic::{self, ElasticLink};
+use crate::Opt;
+use anyhow::{bail, Result};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use shared::db;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Somewhere links can be made searchable.
+#[async_trait]
+pub trait SearchIndex: Send + Sync + fmt::Debug {
+    /// Adds or replaces links. Returns the number of links which couldn't be added.
+    async fn index(&self, links: &[ElasticLink]) -> Result<usize>;
+
+    /// Returns the number of links which couldn't be removed.
+    async fn delete(&self, ids: &[String]) -> Result<usize>;
+
+    /// Removes all links of an OD.
+    async fn delete_opendirectory(&self, od: &str) -> Result<()>;
+
+    async fn count(&self) -> Result<u64>;
+
+    /// IDs of all links in the index, in ascending order.
+    fn ids(&self) -> BoxStream<'_, Result<String>>;
+
+    /// Starts building a fresh copy of the index, which isn't searchable until it's published.
+    /// If `resume` names an unpublished copy which still exists, building continues there instead.
+    async fn rebuild(&self, resume: Option<&str>) -> Result<Box<dyn SearchIndex>>;
+
+    /// Name of the index or copy links are written to.
+    fn name(&self) -> String;
+
+    /// Replaces the live index with this rebuilt one, if it contains `expected` links.
+    /// Otherwise the rebuilt index is deleted. Does nothing for the live index.
+    async fn publish(&self, expected: u64) -> Result<()>;
+
+    /// Whether the index was created by an older version, whose mappings lack fields relied on
+    /// now (e.g. `opendirectory` for [SearchIndex::delete_opendirectory]). Only a full export
+    /// brings such an index up to date.
+    async fn needs_rebuild(&self) -> Result<bool>;
+}
+
+/// The configured [SearchIndex], shared between all tasks.
+#[derive(Debug, Clone)]
+pub struct Search(Arc<dyn SearchIndex>);
+
+/// Placeholder until [open] was called, so options can be parsed without connecting anywhere.
+#[derive(Debug)]
+struct Unopened;
+
+fn unopened<T>() -> Result<T> {
+    bail!("The search index wasn't opened")
+}
+
+#[async_trait]
+impl SearchIndex for Unopened {
+    async fn index(&self, _: &[ElasticLink]) -> Result<usize> {
+        unopened()
+    }
+
+    async fn delete(&self, _: &[String]) -> Result<usize> {
+        unopened()
+    }
+
+    async fn delete_opendirectory(&self, _: &str) -> Result<()> {
+        unopened()
+    }
+
+    async fn count(&self) -> Result<u64> {
+        unopened()
+    }
+
+    fn ids(&self) -> BoxStream<'_, Result<String>> {
+        futures::stream::once(async { unopened() }).boxed()
+    }
+
+    async fn rebuild(&self, _: Option<&str>) -> Result<Box<dyn SearchIndex>> {
+        unopened()
+    }
+
+    fn name(&self) -> String {
+        "unopened".to_string()
+    }
+
+    async fn publish(&self, _: u64) -> Result<()> {
+        unopened()
+    }
+
+    async fn needs_rebuild(&self) -> Result<bool> {
+        unopened()
+    }
+}
+
+impl Default for Search {
+    fn default() -> Self {
+        Search(Arc::new(Unopened))
+    }
+}
+
+impl From<Arc<dyn SearchIndex>> for Search {
+    fn from(index: Arc<dyn SearchIndex>) -> Self {
+        Search(index)
+    }
+}
+
+impl Deref for Search {
+    type Target = dyn SearchIndex;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+pub async fn open(opt: &Opt) -> Result<Search> {
+    let index: Arc<dyn SearchIndex> = match opt.search_backend.as_str() {
+        "elastic" => Arc::new(elastic::Elastic::open(&opt.elastic, opt.index_retention).await?),
+        #[cfg(feature = "tantivy")]
+        "tantivy" => Arc::new(crate::tantivy_index::Tantivy::open(
+            &opt.tantivy_dir,
+            opt.index_retention,
+        )?),
+        #[cfg(not(feature = "tantivy"))]
+        "tantivy" => bail!("The tantivy search backend requires building with --features tantivy"),
+        other => bail!("Unknown search backend '{}'", other),
+    };
+    Ok(index.into())
+}
+
+pub async fn add_links_from_db(opt: &Opt, db: &db::Database, od: &str) -> Result<()> {
+    let od_alive = match db.get_opendirectory(od).await? {
+        Some(od) => !od.is_dead(db.dead_od_threshold),
+        None => bail!("No OD with URL {}", od),
+    };
+    db.get_links(&od)
+        .await?
+        .filter_map(|l| async move {
+            l.ok().filter(|l| !l.is_missing()).and_then(|l| {
+                ElasticLink::new(l, od_alive)
+                    .map_err(|e| warn!("Skipping link: {}", e))
+                    .ok()
+            })
+        })
+        .chunks(5_000)
+        .for_each(|chunk| async move {
+            if let Err(e) = opt.search.index(&chunk).await {
+                warn!("Failed to add links to the search index: {}", e)
+            }
+        })
+        .await;
+    Ok(())
+}
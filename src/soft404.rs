@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// How many bytes of a response are inspected
+pub const SAMPLE_SIZE: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageKind {
+    Parked,
+    LoginWall,
+    ErrorPage,
+}
+
+impl fmt::Display for PageKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PageKind::Parked => write!(f, "parked domain"),
+            PageKind::LoginWall => write!(f, "login wall"),
+            PageKind::ErrorPage => write!(f, "error page"),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SignatureFile {
+    listing: Vec<String>,
+    parked: Vec<String>,
+    login: Vec<String>,
+    error: Vec<String>,
+}
+
+/// Case-insensitive regexes recognizing pages which are served with a success status,
+/// but mean the OD is gone (e.g. registrar parking pages or "account suspended" pages).
+#[derive(Debug, Clone, Default)]
+pub struct PageSignatures {
+    /// Markers of a directory listing. Pages with one of them are never soft 404s, even if they
+    /// e.g. list a file called "password".
+    listing: Vec<Regex>,
+    signatures: Vec<(PageKind, Regex)>,
+}
+
+impl PageSignatures {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            warn!(
+                "Page signatures file {} does not exist, not detecting soft 404s",
+                path.to_string_lossy()
+            );
+            return Ok(Self::default());
+        }
+
+        let file: SignatureFile = serde_json::from_reader(std::fs::File::open(path)?)
+            .with_context(|| format!("Failed to parse {}", path.to_string_lossy()))?;
+
+        let listing = file
+            .listing
+            .iter()
+            .map(|p| build_regex(p))
+            .collect::<Result<_>>()?;
+        let mut signatures = vec![];
+        for (kind, patterns) in [
+            (PageKind::Parked, file.parked),
+            (PageKind::LoginWall, file.login),
+            (PageKind::ErrorPage, file.error),
+        ]
+        .iter()
+        {
+            for pattern in patterns {
+                signatures.push((*kind, build_regex(pattern)?));
+            }
+        }
+        info!("Loaded {} page signatures", signatures.len());
+
+        Ok(Self {
+            listing,
+            signatures,
+        })
+    }
+
+    pub fn classify(&self, body: &str) -> Option<PageKind> {
+        if self.listing.iter().any(|regex| regex.is_match(body)) {
+            return None;
+        }
+        self.signatures
+            .iter()
+            .find(|(_, regex)| regex.is_match(body))
+            .map(|(kind, _)| *kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+}
+
+fn build_regex(pattern: &str) -> Result<Regex> {
+    RegexBuilder::new(pattern)
+        .case_insensitive(true)
+        .build()
+        .with_context(|| format!("Invalid page signature '{}'", pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APACHE_LISTING: &str = r#"<!DOCTYPE HTML PUBLIC "-//W3C//DTD HTML 3.2 Final//EN">
+<html>
+ <head>
+  <title>Index of /backup/404 Not Found (2019)</title>
+ </head>
+ <body>
+<h1>Index of /backup/404 Not Found (2019)</h1>
+  <table>
+   <tr><th valign="top"><img src="/icons/blank.gif" alt="[ICO]"></th><th><a href="?C=N;O=D">Name</a></th><th><a href="?C=M;O=A">Last modified</a></th><th><a href="?C=S;O=A">Size</a></th></tr>
+   <tr><td valign="top"><img src="/icons/back.gif" alt="[PARENTDIR]"></td><td><a href="/backup/">Parent Directory</a></td><td>&nbsp;</td><td align="right">  - </td></tr>
+   <tr><td valign="top"><img src="/icons/text.gif" alt="[TXT]"></td><td><a href="login.html">login.html</a></td><td align="right">2019-03-02 11:12  </td><td align="right">1.2K</td></tr>
+   <tr><td valign="top"><img src="/icons/text.gif" alt="[TXT]"></td><td><a href="input%20type=password.txt">input type=password.txt</a></td><td align="right">2019-03-02 11:12  </td><td align="right">812 </td></tr>
+  </table>
+</body></html>"#;
+
+    const NGINX_LISTING: &str = r#"<html>
+<head><title>Index of /movies/</title></head>
+<body>
+<h1>Index of /movies/</h1><hr><pre><a href="../">../</a>
+<a href="Forbidden%20Planet%20(1956).mkv">Forbidden Planet (1956).mkv</a>                        12-Jan-2020 19:44          2147483648
+<a href="Login%20(2018).mp4">Login (2018).mp4</a>                                  03-Feb-2021 08:01           734003200
+</pre><hr></body>
+</html>"#;
+
+    const PARKED: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>example-od.com - This website is for sale! - example-od Resources and Information.</title>
+<script type="text/javascript" src="https://www.sedoparking.com/frmpark/example-od.com/IONOSParkingUS/park.js"></script>
+</head>
+<body>
+<div class="container-header"><p>The domain name example-od.com is for sale. <a href="https://sedo.com/search/details/?domain=example-od.com">Buy this domain</a></p></div>
+</body>
+</html>"#;
+
+    const LOGIN: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Synology DiskStation</title>
+</head>
+<body>
+<form id="login-form" method="post" action="/webapi/auth.cgi">
+<input type="text" name="username" placeholder="Username" autocomplete="username">
+<input type="password" name="passwd" placeholder="Password" autocomplete="current-password">
+<button type="submit">Sign in</button>
+</form>
+</body>
+</html>"#;
+
+    const NGINX_404: &str = r#"<html>
+<head><title>404 Not Found</title></head>
+<body>
+<center><h1>404 Not Found</h1></center>
+<hr><center>nginx/1.18.0 (Ubuntu)</center>
+</body>
+</html>"#;
+
+    const SUSPENDED: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Account Suspended</title></head>
+<body>
+<div id="container"><h1>This Account has been suspended.</h1>
+<p>Contact your hosting provider for more information.</p></div>
+</body>
+</html>"#;
+
+    fn signatures() -> PageSignatures {
+        PageSignatures::load(Path::new("page_signatures.json")).unwrap()
+    }
+
+    #[test]
+    fn recognizes_soft_404s() {
+        let signatures = signatures();
+        assert_eq!(signatures.classify(PARKED), Some(PageKind::Parked));
+        assert_eq!(signatures.classify(LOGIN), Some(PageKind::LoginWall));
+        assert_eq!(signatures.classify(NGINX_404), Some(PageKind::ErrorPage));
+        assert_eq!(signatures.classify(SUSPENDED), Some(PageKind::ErrorPage));
+    }
+
+    #[test]
+    fn listings_are_not_soft_404s() {
+        let signatures = signatures();
+        assert_eq!(signatures.classify(APACHE_LISTING), None);
+        assert_eq!(signatures.classify(NGINX_LISTING), None);
+    }
+
+    #[test]
+    fn error_codes_are_whole_words() {
+        let signatures = signatures();
+        for title in &[
+            "Mirror 14043",
+            "Files 4040-4049",
+            "ABCD404",
+            "Unforbidden tales",
+        ] {
+            let page = format!("<html><head><title>{}</title></head></html>", title);
+            assert_eq!(signatures.classify(&page), None, "{}", title);
+        }
+        for title in &[
+            "403 Forbidden",
+            "Error 404 - Page Not Found",
+            "Site not found",
+        ] {
+            let page = format!("<html><head><title>{}</title></head></html>", title);
+            assert_eq!(
+                signatures.classify(&page),
+                Some(PageKind::ErrorPage),
+                "{}",
+                title
+            );
+        }
+    }
+}
@@ -52,7 +52,10 @@ pub async fn create_dump(opt: &Opt, db: &Database) -> Result<()> {
     let mut read = Box::pin(
         db::Link::find(&db.db, None, None)
             .await?
-            .filter_map(|r| async { r.ok().filter(|l| ods.contains(&l.opendirectory)) }),
+            .filter_map(|r| async {
+                r.ok()
+                    .filter(|l| ods.contains(&l.opendirectory) && !l.is_missing())
+            }),
     );
 
     let mut count = 0;
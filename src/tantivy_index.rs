@@ -0,0 +1,519 @@
+use crate::elastic::ElasticLink;
+use crate::search::SearchIndex;
+use anyhow::{bail, Context, Result};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tantivy::schema::{Field, IndexRecordOption, Schema, FAST, INDEXED, STORED, STRING, TEXT};
+use tantivy::{DocSet, Index, IndexWriter, Searcher, TantivyDocument, Term, TERMINATED};
+
+/// Memory the index writer may use before flushing
+const WRITER_MEMORY: usize = 50_000_000;
+/// File in the index directory containing the name of the live generation
+const CURRENT_FILE: &str = "current";
+/// How many IDs are read at once when listing them
+const IDS_PAGE_SIZE: usize = 10_000;
+
+#[derive(Clone, Copy)]
+struct Fields {
+    id: Field,
+    url: Field,
+    filename: Field,
+    extension: Field,
+    opendirectory: Field,
+    host: Field,
+    tld: Field,
+    segments: Field,
+    parent: Field,
+    size: Field,
+    last_modified: Field,
+    category: Field,
+    alive: Field,
+}
+
+impl Fields {
+    fn schema() -> Schema {
+        let mut builder = Schema::builder();
+        builder.add_text_field("id", STRING | STORED);
+        builder.add_text_field("url", STRING | STORED);
+        builder.add_text_field("filename", TEXT | STORED);
+        builder.add_text_field("extension", STRING | STORED);
+        builder.add_text_field("opendirectory", STRING | STORED);
+        builder.add_text_field("host", STRING | STORED);
+        builder.add_text_field("tld", STRING);
+        builder.add_text_field("segments", TEXT);
+        builder.add_text_field("parent", TEXT);
+        builder.add_i64_field("size", INDEXED | STORED | FAST);
+        builder.add_date_field("last_modified", INDEXED | STORED | FAST);
+        builder.add_text_field("category", STRING | STORED);
+        builder.add_bool_field("alive", INDEXED | STORED);
+        builder.build()
+    }
+
+    fn from_schema(schema: &Schema) -> Result<Self> {
+        Ok(Self {
+            id: schema.get_field("id")?,
+            url: schema.get_field("url")?,
+            filename: schema.get_field("filename")?,
+            extension: schema.get_field("extension")?,
+            opendirectory: schema.get_field("opendirectory")?,
+            host: schema.get_field("host")?,
+            tld: schema.get_field("tld")?,
+            segments: schema.get_field("segments")?,
+            parent: schema.get_field("parent")?,
+            size: schema.get_field("size")?,
+            last_modified: schema.get_field("last_modified")?,
+            category: schema.get_field("category")?,
+            alive: schema.get_field("alive")?,
+        })
+    }
+
+    fn document(&self, link: &ElasticLink) -> TantivyDocument {
+        let mut doc = TantivyDocument::default();
+        doc.add_text(self.id, &link.id);
+        doc.add_text(self.url, &link.url);
+        doc.add_text(self.filename, &link.filename);
+        doc.add_text(self.opendirectory, &link.opendirectory);
+        for segment in &link.segments {
+            doc.add_text(self.segments, segment);
+        }
+        let optional_text = [
+            (self.extension, &link.extension),
+            (self.host, &link.host),
+            (self.tld, &link.tld),
+            (self.parent, &link.parent),
+            (self.category, &link.category),
+        ];
+        for (field, value) in optional_text.iter() {
+            if let Some(value) = value {
+                doc.add_text(*field, value.to_lowercase());
+            }
+        }
+        if let Some(size) = link.size {
+            doc.add_i64(self.size, size);
+        }
+        if let Some(last_modified) = link.last_modified {
+            doc.add_date(
+                self.last_modified,
+                tantivy::DateTime::from_timestamp_secs(last_modified.timestamp()),
+            );
+        }
+        doc.add_bool(self.alive, link.alive);
+        doc
+    }
+}
+
+/// The first [IDS_PAGE_SIZE] IDs of live documents after `after`, in ascending order.
+///
+/// Each segment's term dictionary is sorted, so only that many terms are read per segment.
+/// Deleted documents stay in the dictionary until segments are merged, so their postings are
+/// checked against the segment's alive documents.
+fn ids_after(searcher: &Searcher, field: Field, after: Option<&str>) -> Result<Vec<String>> {
+    let mut ids = vec![];
+    for segment in searcher.segment_readers() {
+        let inverted_index = segment.inverted_index(field)?;
+        let terms = inverted_index.terms();
+        let mut stream = match after {
+            Some(after) => terms.range().gt(after.as_bytes()).into_stream()?,
+            None => terms.stream()?,
+        };
+        let mut found = 0;
+        while found < IDS_PAGE_SIZE && stream.advance() {
+            if let Some(alive) = segment.alive_bitset() {
+                let mut postings = inverted_index
+                    .read_postings_from_terminfo(stream.value(), IndexRecordOption::Basic)?;
+                let mut doc = postings.doc();
+                while doc != TERMINATED && !alive.is_alive(doc) {
+                    doc = postings.advance();
+                }
+                if doc == TERMINATED {
+                    continue;
+                }
+            }
+            ids.push(String::from_utf8(stream.key().to_vec())?);
+            found += 1;
+        }
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    ids.truncate(IDS_PAGE_SIZE);
+    Ok(ids)
+}
+
+/// One version of the index, in its own directory.
+struct Generation {
+    name: String,
+    index: Index,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+}
+
+impl fmt::Debug for Generation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Generation({})", self.name)
+    }
+}
+
+impl Generation {
+    fn create(dir: &Path) -> Result<Self> {
+        let name = format!("links-{}", chrono::Utc::now().format("%Y%m%d%H%M%S%3f"));
+        let path = dir.join(&name);
+        std::fs::create_dir_all(&path)?;
+        info!("Creating search index {}", path.to_string_lossy());
+        Self::with_index(name, Index::create_in_dir(&path, Fields::schema())?)
+    }
+
+    fn open(dir: &Path, name: &str) -> Result<Self> {
+        let path = dir.join(name);
+        let index = Index::open_in_dir(&path)
+            .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+        Self::with_index(name.to_string(), index)
+    }
+
+    fn with_index(name: String, index: Index) -> Result<Self> {
+        let fields = Fields::from_schema(&index.schema())?;
+        let writer = Mutex::new(index.writer(WRITER_MEMORY)?);
+        Ok(Self {
+            name,
+            index,
+            writer,
+            fields,
+        })
+    }
+
+    /// Applies changes and commits them, so they're visible to new readers.
+    fn write(&self, changes: impl FnOnce(&IndexWriter, &Fields) -> usize) -> Result<usize> {
+        let mut writer = self.writer.lock().unwrap();
+        let failed = changes(&writer, &self.fields);
+        writer.commit()?;
+        Ok(failed)
+    }
+}
+
+/// [SearchIndex] embedded in the process, for deployments without an Elasticsearch cluster.
+///
+/// Like the Elasticsearch index, rebuilds go into a new generation which replaces the live one
+/// when it's published.
+#[derive(Debug)]
+pub struct Tantivy {
+    dir: PathBuf,
+    /// Number of generations kept when publishing
+    retention: usize,
+    live: Arc<RwLock<Arc<Generation>>>,
+    rebuilding: Option<Arc<Generation>>,
+}
+
+impl Tantivy {
+    pub fn open(dir: &Path, retention: usize) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let current = dir.join(CURRENT_FILE);
+        let generation = if current.exists() {
+            Generation::open(dir, std::fs::read_to_string(&current)?.trim())?
+        } else {
+            let generation = Generation::create(dir)?;
+            std::fs::write(&current, &generation.name)?;
+            generation
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            retention,
+            live: Arc::new(RwLock::new(Arc::new(generation))),
+            rebuilding: None,
+        })
+    }
+
+    fn generation(&self) -> Arc<Generation> {
+        match &self.rebuilding {
+            Some(generation) => generation.clone(),
+            None => self.live.read().unwrap().clone(),
+        }
+    }
+
+    /// Tantivy blocks while committing, so changes are written on a blocking thread.
+    async fn write<F>(&self, changes: F) -> Result<usize>
+    where
+        F: FnOnce(&IndexWriter, &Fields) -> usize + Send + 'static,
+    {
+        let generation = self.generation();
+        async_std::task::spawn_blocking(move || generation.write(changes)).await
+    }
+}
+
+#[async_trait]
+impl SearchIndex for Tantivy {
+    async fn index(&self, links: &[ElasticLink]) -> Result<usize> {
+        info!("Adding {} links to the search index", links.len());
+        let links = links.to_vec();
+        self.write(move |writer, fields| {
+            let mut failed = 0;
+            for link in &links {
+                writer.delete_term(Term::from_field_text(fields.id, &link.id));
+                if let Err(e) = writer.add_document(fields.document(link)) {
+                    warn!("Failed to add {}: {}", link.url, e);
+                    failed += 1;
+                }
+            }
+            failed
+        })
+        .await
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<usize> {
+        info!("Removing {} links from the search index", ids.len());
+        let ids = ids.to_vec();
+        self.write(move |writer, fields| {
+            for id in &ids {
+                writer.delete_term(Term::from_field_text(fields.id, id));
+            }
+            0
+        })
+        .await
+    }
+
+    async fn delete_opendirectory(&self, od: &str) -> Result<()> {
+        info!("Removing links of OD {} from the search index", od);
+        let od = od.to_string();
+        self.write(move |writer, fields| {
+            writer.delete_term(Term::from_field_text(fields.opendirectory, &od));
+            0
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<u64> {
+        let generation = self.generation();
+        async_std::task::spawn_blocking(move || {
+            Ok(generation.index.reader()?.searcher().num_docs())
+        })
+        .await
+    }
+
+    fn ids(&self) -> BoxStream<'_, Result<String>> {
+        let generation = self.generation();
+        let field = generation.fields.id;
+        // Every page comes from the same searcher, so commits meanwhile don't shift them
+        let searcher = match generation.index.reader() {
+            Ok(reader) => reader.searcher(),
+            Err(e) => return futures::stream::once(async { Err(e.into()) }).boxed(),
+        };
+        futures::stream::try_unfold(Some((searcher, None)), move |state| async move {
+            let (searcher, after) = match state {
+                Some(state) => state,
+                None => return Ok(None),
+            };
+            let page = async_std::task::spawn_blocking({
+                let searcher = searcher.clone();
+                move || ids_after(&searcher, field, after.as_deref())
+            })
+            .await?;
+            let next = page.last().cloned().map(|last| (searcher, Some(last)));
+            let page = futures::stream::iter(page.into_iter().map(Ok));
+            Ok::<_, anyhow::Error>(Some((page, next)))
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn rebuild(&self, resume: Option<&str>) -> Result<Box<dyn SearchIndex>> {
+        let dir = self.dir.clone();
+        let live = self.live.read().unwrap().name.clone();
+        let resume = resume
+            .filter(|name| *name != live && dir.join(name).is_dir())
+            .map(str::to_string);
+        let generation = async_std::task::spawn_blocking(move || match resume {
+            Some(name) => {
+                info!("Resuming search index {}", name);
+                Generation::open(&dir, &name)
+            }
+            None => Generation::create(&dir),
+        })
+        .await?;
+        Ok(Box::new(Self {
+            dir: self.dir.clone(),
+            retention: self.retention,
+            live: self.live.clone(),
+            rebuilding: Some(Arc::new(generation)),
+        }))
+    }
+
+    fn name(&self) -> String {
+        self.generation().name.clone()
+    }
+
+    async fn publish(&self, expected: u64) -> Result<()> {
+        let generation = match &self.rebuilding {
+            Some(generation) => generation.clone(),
+            None => return Ok(()),
+        };
+        let indexed = self.count().await?;
+        if indexed != expected {
+            // A resumed export would only add to the wrong documents
+            std::fs::remove_dir_all(self.dir.join(&generation.name))?;
+            bail!(
+                "{} contained {} documents, but {} were expected. Deleted it and kept the previous index",
+                generation.name,
+                indexed,
+                expected
+            );
+        }
+
+        info!("Switching the search index to {}", generation.name);
+        let current = self.dir.join(CURRENT_FILE);
+        let temporary = self.dir.join(format!("{}.tmp", CURRENT_FILE));
+        std::fs::write(&temporary, &generation.name)?;
+        std::fs::rename(&temporary, &current)?;
+        *self.live.write().unwrap() = generation.clone();
+
+        let mut generations: Vec<String> = std::fs::read_dir(&self.dir)?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().is_dir())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with("links-"))
+            .collect();
+        // The timestamp suffix sorts chronologically
+        generations.sort_unstable_by(|a, b| b.cmp(a));
+        for name in generations.iter().skip(self.retention) {
+            if *name != generation.name {
+                info!("Deleting old search index {}", name);
+                std::fs::remove_dir_all(self.dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn needs_rebuild(&self) -> Result<bool> {
+        // The embedded index always had all fields
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::db::Link;
+
+    fn link(od: &str, path: &str) -> ElasticLink {
+        let link = Link {
+            id: Some(wither::bson::oid::ObjectId::new()),
+            opendirectory: od.to_string(),
+            url: format!("{}{}", od, path),
+            missing: 0,
+            size: None,
+            last_modified: None,
+            category: None,
+        };
+        ElasticLink::new(link, true).unwrap()
+    }
+
+    async fn ids(index: &dyn SearchIndex) -> Vec<String> {
+        index.ids().try_collect().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn links_are_added_replaced_and_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Tantivy::open(dir.path(), 2).unwrap();
+        let first = link("http://a.test/", "1.iso");
+        let second = link("http://a.test/", "2.iso");
+        let other = link("http://b.test/", "3.iso");
+        let links = [first.clone(), second.clone(), other.clone()];
+
+        assert_eq!(index.index(&links).await.unwrap(), 0);
+        // Indexing a link again replaces its document
+        assert_eq!(index.index(&links[..1]).await.unwrap(), 0);
+        assert_eq!(index.count().await.unwrap(), 3);
+        let mut expected = vec![first.id.clone(), second.id.clone(), other.id.clone()];
+        expected.sort();
+        assert_eq!(ids(&index).await, expected);
+
+        assert_eq!(
+            index
+                .delete(std::slice::from_ref(&second.id))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(index.count().await.unwrap(), 2);
+
+        index.delete_opendirectory("http://a.test/").await.unwrap();
+        assert_eq!(ids(&index).await, vec![other.id]);
+    }
+
+    #[async_std::test]
+    async fn ids_are_listed_in_pages_across_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Tantivy::open(dir.path(), 2).unwrap();
+        let links: Vec<_> = (0..IDS_PAGE_SIZE + 2_000)
+            .map(|i| link("http://a.test/", &format!("{}.iso", i)))
+            .collect();
+        // Each commit writes another segment
+        let (first, second) = links.split_at(links.len() / 2);
+        index.index(first).await.unwrap();
+        index.index(second).await.unwrap();
+        let deleted: Vec<String> = links.iter().step_by(3).map(|l| l.id.clone()).collect();
+        index.delete(&deleted).await.unwrap();
+
+        let mut expected: Vec<String> = links
+            .iter()
+            .map(|l| l.id.clone())
+            .filter(|id| !deleted.contains(id))
+            .collect();
+        expected.sort();
+        assert_eq!(ids(&index).await, expected);
+    }
+
+    #[async_std::test]
+    async fn published_rebuilds_replace_the_live_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Tantivy::open(dir.path(), 1).unwrap();
+        let old = link("http://a.test/", "old.iso");
+        index.index(&[old]).await.unwrap();
+
+        async_std::task::sleep(std::time::Duration::from_millis(2)).await;
+        let rebuilt = index.rebuild(None).await.unwrap();
+        let new = link("http://a.test/", "new.iso");
+        rebuilt.index(std::slice::from_ref(&new)).await.unwrap();
+        // Not searchable before it's published
+        assert_eq!(index.count().await.unwrap(), 1);
+        assert_ne!(index.ids().next().await.unwrap().unwrap(), new.id);
+
+        rebuilt.publish(1).await.unwrap();
+        assert_eq!(index.name(), rebuilt.name());
+        assert_eq!(ids(&index).await, vec![new.id.clone()]);
+        // Only the published generation is kept and opened again
+        let generations = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().is_dir())
+            .count();
+        assert_eq!(generations, 1);
+        drop(rebuilt);
+        drop(index);
+        let reopened = Tantivy::open(dir.path(), 1).unwrap();
+        assert_eq!(ids(&reopened).await, vec![new.id]);
+    }
+
+    #[async_std::test]
+    async fn incomplete_rebuilds_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = Tantivy::open(dir.path(), 2).unwrap();
+        let live = index.name();
+        // Generations are named after the millisecond they were created in
+        async_std::task::sleep(std::time::Duration::from_millis(2)).await;
+        let rebuilt = index.rebuild(None).await.unwrap();
+        rebuilt
+            .index(&[link("http://a.test/", "1.iso")])
+            .await
+            .unwrap();
+
+        assert!(rebuilt.publish(2).await.is_err());
+        assert!(!dir.path().join(rebuilt.name()).exists());
+        assert_eq!(index.name(), live);
+        assert_eq!(
+            std::fs::read_to_string(dir.path().join(CURRENT_FILE)).unwrap(),
+            live
+        );
+    }
+}
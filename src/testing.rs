@@ -0,0 +1,400 @@
+//! Local stand-ins for the servers the crawler talks to, for tests.
+
+use crate::elastic::ElasticLink;
+use crate::search::SearchIndex;
+use crate::Opt;
+use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslAcceptor, SslMethod};
+use openssl::x509::{X509Builder, X509NameBuilder};
+use shared::db::{Database, Link, OpenDirectory};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use structopt::StructOpt;
+use wither::bson::oid::ObjectId;
+use wither::Model;
+
+/// Host name the stand-ins are reached by through proxies. It doesn't resolve, so requests to it
+/// only succeed through a proxy.
+pub const HOST: &str = "od.test";
+
+/// Options as if the crawler was started without arguments.
+pub fn opt() -> Opt {
+    Opt::from_iter(&["odcrawler_discovery"])
+}
+
+/// An address nothing listens on.
+pub fn closed_port() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Handles every connection to a local port on its own thread.
+pub fn listen(handler: impl Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let handler = handler.clone();
+            thread::spawn(move || handler(stream));
+        }
+    });
+    address
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+}
+
+fn read_request<S: Read + Write>(reader: &mut BufReader<S>) -> Option<Request> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).ok()? == 0 {
+        return None;
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+    let mut length = 0;
+    let mut expect_continue = false;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().ok()?;
+            } else if name.eq_ignore_ascii_case("expect") {
+                expect_continue = value.trim().eq_ignore_ascii_case("100-continue");
+            }
+        }
+    }
+    // curl waits a second for this before sending the body anyway
+    if expect_continue {
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .ok()?;
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).to_string(),
+    })
+}
+
+/// A complete HTTP response with a JSON body.
+pub fn response(status: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// A redirect to `location`.
+pub fn redirect(status: &str, location: &str) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+        status, location
+    )
+}
+
+type Handler = dyn Fn(&Request) -> String + Send + Sync;
+
+fn serve_http(stream: impl Read + Write, handler: &Handler) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    while let Some(request) = read_request(&mut stream) {
+        let mut response = handler(&request);
+        if request.method == "HEAD" {
+            if let Some(end) = response.find("\r\n\r\n") {
+                response.truncate(end + 4);
+            }
+        }
+        stream.get_mut().write_all(response.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// An HTTP server answering every request with `handler`.
+pub fn http_server(handler: impl Fn(&Request) -> String + Send + Sync + 'static) -> SocketAddr {
+    listen(move |stream| serve_http(stream, &handler))
+}
+
+/// Like [http_server], but with TLS and a self-signed certificate for [HOST].
+pub fn https_server(handler: impl Fn(&Request) -> String + Send + Sync + 'static) -> SocketAddr {
+    let acceptor = self_signed_acceptor();
+    listen(move |stream| {
+        let stream = acceptor
+            .accept(stream)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        serve_http(stream, &handler)
+    })
+}
+
+fn self_signed_acceptor() -> SslAcceptor {
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", HOST).unwrap();
+    let name = name.build();
+    let mut certificate = X509Builder::new().unwrap();
+    certificate.set_version(2).unwrap();
+    certificate.set_subject_name(&name).unwrap();
+    certificate.set_issuer_name(&name).unwrap();
+    certificate.set_pubkey(&key).unwrap();
+    certificate
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    certificate
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    certificate.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
+    acceptor.set_private_key(&key).unwrap();
+    acceptor.set_certificate(&certificate.build()).unwrap();
+    acceptor.build()
+}
+
+/// Copies data both ways until either side closes.
+fn tunnel(client: TcpStream, server: TcpStream) -> io::Result<()> {
+    let (mut client_read, mut server_write) = (client.try_clone()?, server.try_clone()?);
+    let upstream = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut server_write);
+        let _ = server_write.shutdown(Shutdown::Write);
+    });
+    let (mut server_read, mut client_write) = (server, client);
+    let _ = io::copy(&mut server_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Write);
+    let _ = upstream.join();
+    Ok(())
+}
+
+/// An HTTP proxy which tunnels every CONNECT to `target`, whatever host was asked for.
+/// Counts the connections it tunneled, and answers 502 if `target` can't be connected to.
+pub fn http_proxy(target: SocketAddr) -> (SocketAddr, Arc<AtomicUsize>) {
+    let tunneled = Arc::new(AtomicUsize::new(0));
+    let counter = tunneled.clone();
+    let address = listen(move |stream| {
+        let mut reader = BufReader::new(stream);
+        let request = match read_request(&mut reader) {
+            Some(request) => request,
+            None => return Ok(()),
+        };
+        let mut client = reader.into_inner();
+        if request.method != "CONNECT" {
+            return client.write_all(response("405 Method Not Allowed", "").as_bytes());
+        }
+        let server = match TcpStream::connect(target) {
+            Ok(server) => server,
+            Err(_) => return client.write_all(response("502 Bad Gateway", "").as_bytes()),
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+        tunnel(client, server)
+    });
+    (address, tunneled)
+}
+
+/// A SOCKS5 proxy without authentication which connects every request to `target`.
+/// Counts the connections it tunneled, and replies "connection refused" if `target` can't be
+/// connected to.
+pub fn socks5_proxy(target: SocketAddr) -> (SocketAddr, Arc<AtomicUsize>) {
+    let tunneled = Arc::new(AtomicUsize::new(0));
+    let counter = tunneled.clone();
+    let address = listen(move |mut client| {
+        let mut greeting = [0; 2];
+        client.read_exact(&mut greeting)?;
+        let mut methods = vec![0; greeting[1] as usize];
+        client.read_exact(&mut methods)?;
+        client.write_all(&[5, 0])?;
+
+        let mut request = [0; 4];
+        client.read_exact(&mut request)?;
+        let address_length = match request[3] {
+            1 => 4,
+            4 => 16,
+            _ => {
+                let mut length = [0; 1];
+                client.read_exact(&mut length)?;
+                length[0] as usize
+            }
+        };
+        // The address and port are ignored
+        let mut destination = vec![0; address_length + 2];
+        client.read_exact(&mut destination)?;
+        let server = match TcpStream::connect(target) {
+            Ok(server) => server,
+            Err(_) => return client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]),
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+        client.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])?;
+        tunnel(client, server)
+    });
+    (address, tunneled)
+}
+
+/// A minimal FTP server for reachability checks. It knows the directories and files (with sizes)
+/// it was given as absolute paths, but can't transfer anything and doesn't support TLS.
+pub fn ftp_server(directories: &[&str], files: &[(&str, u64)]) -> SocketAddr {
+    let directories: Vec<String> = directories.iter().map(|d| d.to_string()).collect();
+    let files: Vec<(String, u64)> = files.iter().map(|(f, s)| (f.to_string(), *s)).collect();
+    listen(move |stream| {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut cwd = "/".to_string();
+        writer.write_all(b"220 Stand-in FTP server\r\n")?;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 {
+            let (command, argument) = match line.trim_end().split_once(' ') {
+                Some((command, argument)) => (command.to_ascii_uppercase(), argument.to_string()),
+                None => (line.trim_end().to_ascii_uppercase(), String::new()),
+            };
+            line.clear();
+            let path = if argument.starts_with('/') {
+                argument
+            } else {
+                format!("{}/{}", cwd.trim_end_matches('/'), argument)
+            };
+            let size = files.iter().find(|(f, _)| *f == path).map(|(_, s)| *s);
+            let reply = match command.as_str() {
+                "USER" => "331 Password required".to_string(),
+                "PASS" => "230 Logged in".to_string(),
+                "PWD" => format!("257 \"{}\" is the current directory", cwd),
+                "CWD" if path == "/" || directories.contains(&path) => {
+                    cwd = path;
+                    "250 Directory changed".to_string()
+                }
+                "CWD" => "550 No such directory".to_string(),
+                "TYPE" => "200 Type set".to_string(),
+                "MDTM" if size.is_some() => "213 20200101000000".to_string(),
+                "SIZE" if size.is_some() => format!("213 {}", size.unwrap()),
+                "MDTM" | "SIZE" => "550 No such file".to_string(),
+                "REST" => "350 Restarting".to_string(),
+                "QUIT" => {
+                    writer.write_all(b"221 Bye\r\n")?;
+                    return Ok(());
+                }
+                _ => "502 Not implemented".to_string(),
+            };
+            writer.write_all(format!("{}\r\n", reply).as_bytes())?;
+        }
+        Ok(())
+    })
+}
+
+/// A database of its own on the local MongoDB. Tests using it are ignored by default, run them
+/// with `cargo test -- --include-ignored` while a MongoDB is running.
+pub async fn database() -> Database {
+    let name = format!("odcrawler-test-{}", ObjectId::new());
+    Database::open(&name).await.unwrap()
+}
+
+/// Saves an OD which wasn't checked yet and failed `unreachable` checks before.
+pub async fn save_opendirectory(db: &Database, url: &str, unreachable: i32) -> OpenDirectory {
+    let mut od = OpenDirectory {
+        id: None,
+        url: url.to_string(),
+        unreachable,
+        last_checked: None,
+        file_success_rate: None,
+        final_url: None,
+        pinned: None,
+        dead_threshold: None,
+    };
+    od.save(&db.db, None).await.unwrap();
+    od
+}
+
+/// Saves a link to a file of `od`.
+pub async fn save_link(db: &Database, od: &OpenDirectory, name: &str) -> Link {
+    let mut link = Link {
+        id: None,
+        opendirectory: od.url.clone(),
+        url: format!("{}{}", od.url, name),
+        missing: 0,
+        size: None,
+        last_modified: None,
+        category: None,
+    };
+    link.save(&db.db, None).await.unwrap();
+    link
+}
+
+/// A search index which records what is added to and removed from it.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    /// IDs of each `index` call
+    pub indexed: Mutex<Vec<Vec<String>>>,
+    pub deleted: Mutex<Vec<String>>,
+    /// ODs whose links were removed
+    pub deleted_opendirectories: Mutex<Vec<String>>,
+}
+
+/// Tests only add and remove documents.
+fn unused<T>() -> Result<T> {
+    anyhow::bail!("Not used by tests")
+}
+
+#[async_trait]
+impl SearchIndex for Recorder {
+    async fn index(&self, links: &[ElasticLink]) -> Result<usize> {
+        let ids = links.iter().map(|l| l.id.clone()).collect();
+        self.indexed.lock().unwrap().push(ids);
+        Ok(0)
+    }
+
+    async fn delete(&self, ids: &[String]) -> Result<usize> {
+        self.deleted.lock().unwrap().extend_from_slice(ids);
+        Ok(0)
+    }
+
+    async fn delete_opendirectory(&self, opendirectory: &str) -> Result<()> {
+        let mut deleted = self.deleted_opendirectories.lock().unwrap();
+        deleted.push(opendirectory.to_string());
+        Ok(())
+    }
+
+    async fn count(&self) -> Result<u64> {
+        unused()
+    }
+
+    fn ids(&self) -> BoxStream<'_, Result<String>> {
+        futures::stream::once(async { unused() }).boxed()
+    }
+
+    async fn rebuild(&self, _: Option<&str>) -> Result<Box<dyn SearchIndex>> {
+        unused()
+    }
+
+    fn name(&self) -> String {
+        "recorder".to_string()
+    }
+
+    async fn publish(&self, _: u64) -> Result<()> {
+        unused()
+    }
+
+    async fn needs_rebuild(&self) -> Result<bool> {
+        unused()
+    }
+}
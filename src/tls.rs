@@ -0,0 +1,163 @@
+use anyhow::{bail, Context, Result};
+use isahc::http::Uri;
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameRef, X509VerifyResult};
+use shared::db::Certificate;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use url::Url;
+
+/// Connects to the host of an HTTPS link and reads its certificate.
+///
+/// isahc doesn't expose certificates, so this makes its own connection (through `proxy`, if set).
+pub async fn fetch_certificate(
+    link: &str,
+    proxy: Option<Uri>,
+    timeout: Duration,
+) -> Result<Certificate> {
+    let url = Url::parse(link)?;
+    let host = match url.host_str() {
+        Some(host) => host.to_string(),
+        None => bail!("No host in {}", link),
+    };
+    let port = url.port_or_known_default().unwrap_or(443);
+    async_std::task::spawn_blocking(move || read_certificate(&host, port, proxy, timeout)).await
+}
+
+fn read_certificate(
+    host: &str,
+    port: u16,
+    proxy: Option<Uri>,
+    timeout: Duration,
+) -> Result<Certificate> {
+    let stream = match proxy {
+        Some(proxy) => connect_via_proxy(&proxy, host, port, timeout)?,
+        None => connect(host, port, timeout)?,
+    };
+
+    // Don't fail on invalid certificates, only record whether they are valid
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    builder.set_verify(SslVerifyMode::NONE);
+    let stream = builder.build().configure()?.connect(host, stream)?;
+    let ssl = stream.ssl();
+    let cert = ssl
+        .peer_certificate()
+        .context("Server didn't present a certificate")?;
+
+    let until_expiry = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+    let not_after = chrono::Utc::now()
+        + chrono::Duration::days(until_expiry.days.into())
+        + chrono::Duration::seconds(until_expiry.secs.into());
+    let fingerprint: Vec<String> = cert
+        .digest(MessageDigest::sha256())?
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+
+    Ok(Certificate {
+        id: None,
+        host: host.to_string(),
+        subject: format_name(cert.subject_name()),
+        issuer: format_name(cert.issuer_name()),
+        not_after: not_after.into(),
+        valid: ssl.verify_result() == X509VerifyResult::OK,
+        fingerprint: fingerprint.join(":"),
+        previous_fingerprint: None,
+        changed: None,
+        checked: chrono::Utc::now().into(),
+    })
+}
+
+fn format_name(name: &X509NameRef) -> String {
+    let entries: Vec<String> = name
+        .entries()
+        .map(|e| {
+            let key = e.object().nid().short_name().unwrap_or("?");
+            let value = e
+                .data()
+                .as_utf8()
+                .map(|v| v.to_string())
+                .unwrap_or_default();
+            format!("{}={}", key, value)
+        })
+        .collect();
+    entries.join(", ")
+}
+
+fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let addr = match (host, port).to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => bail!("Couldn't resolve {}", host),
+    };
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Opens a tunnel through an HTTP (CONNECT) or SOCKS5 proxy.
+fn connect_via_proxy(proxy: &Uri, host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let proxy_host = proxy.host().context("Proxy without host")?;
+    let scheme = proxy.scheme_str().unwrap_or("http");
+    let default_port = if scheme.starts_with("socks") {
+        1080
+    } else {
+        8080
+    };
+    let mut stream = connect(
+        proxy_host,
+        proxy.port_u16().unwrap_or(default_port),
+        timeout,
+    )?;
+
+    if scheme.starts_with("socks5") {
+        stream.write_all(&[5, 1, 0])?;
+        let mut greeting = [0; 2];
+        stream.read_exact(&mut greeting)?;
+        if greeting != [5, 0] {
+            bail!("SOCKS proxy requires authentication");
+        }
+
+        let mut request = vec![5, 1, 0, 3, host.len() as u8];
+        request.extend(host.as_bytes());
+        request.extend(&port.to_be_bytes());
+        stream.write_all(&request)?;
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            bail!("SOCKS proxy refused to connect: {}", reply[1]);
+        }
+        let address_len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            _ => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+        };
+        // Bound address and port, which we don't need
+        stream.read_exact(&mut vec![0; address_len + 2])?;
+    } else {
+        write!(
+            stream,
+            "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n",
+            host, port
+        )?;
+        let mut response = vec![];
+        let mut byte = [0; 1];
+        while !response.ends_with(b"\r\n\r\n") {
+            stream.read_exact(&mut byte)?;
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status_line = response.lines().next().unwrap_or_default();
+        if !status_line.contains(" 200") {
+            bail!("Proxy refused to connect: {}", status_line);
+        }
+    }
+    Ok(stream)
+}
@@ -1,9 +1,39 @@
 use rocket::futures::StreamExt;
+use rocket::response::Debug;
 use rocket::{get, routes, Rocket, State};
 use rocket_contrib::json::Json;
 use rocket_contrib::templates::Template;
 use shared::db;
 use shared::db::Stats as DbStats;
+use shared::media::Category;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+mod search;
+#[cfg(feature = "tantivy")]
+mod tantivy_search;
+
+/// Options can also be given as environment variables, e.g. ELASTIC_URL.
+#[derive(StructOpt, Debug)]
+pub struct Opt {
+    /// Where the crawler makes links searchable
+    #[structopt(
+        long,
+        env = "SEARCH_BACKEND",
+        default_value = "elastic",
+        possible_values = &["elastic", "tantivy"]
+    )]
+    search_backend: String,
+
+    /// Directory of the crawler's embedded search index, if the tantivy backend is used
+    #[structopt(long, env = "TANTIVY_DIR", default_value = "search-index")]
+    #[cfg_attr(not(feature = "tantivy"), allow(dead_code))]
+    tantivy_dir: PathBuf,
+
+    #[structopt(flatten)]
+    elastic: shared::elastic::Config,
+}
 
 #[derive(serde::Serialize)]
 struct Stats {
@@ -20,7 +50,7 @@ async fn stats_json(db: State<'_, db::Database>) -> Json<Stats> {
     let load = mprober_lib::load_average::get_load_average().unwrap_or_default();
     let mem = mprober_lib::memory::free().unwrap_or_default().mem;
     let stats = Stats {
-        db: db.stats().await.unwrap(),
+        db: db.refreshed().await.unwrap().stats().await.unwrap(),
         load_one: load.one,
         load_five: load.five,
         load_fifteen: load.fifteen,
@@ -36,10 +66,34 @@ async fn stats(db: State<'_, db::Database>) -> Template {
     Template::render("index", &stats)
 }
 
+#[derive(serde::Serialize)]
+struct Certificate {
+    subject: String,
+    issuer: String,
+    expires: String,
+    valid: bool,
+    expires_soon: bool,
+    changed_recently: bool,
+}
+
+impl From<db::Certificate> for Certificate {
+    fn from(c: db::Certificate) -> Self {
+        Self {
+            expires: c.not_after.0.format("%F").to_string(),
+            valid: c.valid,
+            expires_soon: c.expires_soon(),
+            changed_recently: c.changed_recently(),
+            subject: c.subject,
+            issuer: c.issuer,
+        }
+    }
+}
+
 #[derive(serde::Serialize)]
 struct OD {
     url: String,
     dead: bool,
+    certificate: Option<Certificate>,
 }
 
 #[derive(serde::Serialize)]
@@ -49,14 +103,27 @@ struct ODs {
 
 #[get("/ods/json")]
 async fn ods_json(db: State<'_, db::Database>) -> Json<ODs> {
+    // The crawler may have changed the threshold since this process started
+    let threshold = db.refreshed().await.unwrap().dead_od_threshold;
+    let certificates: HashMap<String, db::Certificate> = db
+        .get_certificates()
+        .await
+        .unwrap()
+        .filter_map(|r| async { r.ok() })
+        .map(|c| (c.host.clone(), c))
+        .collect()
+        .await;
     let ods = db
         .get_opendirectories(true)
         .await
         .unwrap()
         .filter_map(|r| async { r.ok() })
         .map(|od| OD {
+            certificate: shared::host_of(&od.url)
+                .and_then(|host| certificates.get(&host).cloned())
+                .map(Certificate::from),
+            dead: od.is_dead(threshold),
             url: od.url,
-            dead: od.unreachable >= shared::DEAD_OD_THRESHOLD,
         })
         .collect()
         .await;
@@ -71,35 +138,349 @@ async fn ods(db: State<'_, db::Database>) -> Template {
 
 #[derive(serde::Serialize)]
 struct Links {
+    url: String,
     links: Vec<String>,
+    certificate: Option<Certificate>,
+    /// The category links are filtered by
+    category: Option<Category>,
+    categories: Vec<Category>,
 }
 
-#[get("/od/json?<url>")]
-async fn links_json(db: State<'_, db::Database>, url: &str) -> Json<Links> {
+#[get("/od/json?<url>&<category>")]
+async fn links_json(db: State<'_, db::Database>, url: &str, category: Option<&str>) -> Json<Links> {
+    let category = category.and_then(|c| c.parse::<Category>().ok());
     let links = db
         .get_links(&url)
         .await
         .unwrap()
-        .filter_map(|r| async { r.ok() })
+        .filter_map(|r| async move {
+            r.ok()
+                .filter(|l| category.is_none() || l.category == category)
+        })
         .map(|l| l.url)
         .collect()
         .await;
-    Json(Links { links })
+    let certificate = match shared::host_of(url) {
+        Some(host) => db.get_certificate(&host).await.unwrap(),
+        None => None,
+    };
+    Json(Links {
+        url: url.to_string(),
+        links,
+        certificate: certificate.map(Certificate::from),
+        category,
+        categories: Category::ALL.to_vec(),
+    })
 }
 
-#[get("/od?<url>")]
-async fn links(db: State<'_, db::Database>, url: &str) -> Template {
-    let links = links_json(db, url).await.into_inner();
+#[get("/od?<url>&<category>")]
+async fn links(db: State<'_, db::Database>, url: &str, category: Option<&str>) -> Template {
+    let links = links_json(db, url, category).await.into_inner();
     Template::render("links", &links)
 }
 
+#[derive(serde::Serialize)]
+struct SearchResult {
+    url: String,
+    filename: String,
+    size: Option<i64>,
+    category: Option<String>,
+    opendirectory: String,
+    /// Whether the OD is alive and the link wasn't missing when it was last checked
+    alive: bool,
+}
+
+#[derive(serde::Serialize)]
+struct SearchResults {
+    query: search::Query,
+    total: u64,
+    page: u64,
+    pages: u64,
+    results: Vec<SearchResult>,
+    /// Query strings of the neighbouring pages, if there are any
+    previous: Option<String>,
+    next: Option<String>,
+    categories: Vec<Category>,
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/search/json?<q>&<extension>&<category>&<host>&<min_size>&<max_size>&<page>")]
+async fn search_json(
+    index: State<'_, Box<dyn search::Searcher>>,
+    liveness: State<'_, Box<dyn search::Liveness>>,
+    q: Option<String>,
+    extension: Option<String>,
+    category: Option<String>,
+    host: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    page: Option<u64>,
+) -> Result<Json<SearchResults>, Debug<anyhow::Error>> {
+    let query = search::Query {
+        q,
+        extension,
+        category,
+        host,
+        min_size,
+        max_size,
+    };
+    let page = page.unwrap_or(1).clamp(1, search::MAX_PAGE);
+    let hits = index.search(&query, page).await?;
+    let ods: HashSet<String> = hits
+        .documents
+        .iter()
+        .map(|d| d.opendirectory.clone())
+        .collect();
+    let dead_ods = liveness.dead(&ods).await?;
+    let results = hits
+        .documents
+        .into_iter()
+        .map(|document| SearchResult {
+            alive: document.alive && !dead_ods.contains(&document.opendirectory),
+            url: document.url,
+            filename: document.filename,
+            size: document.size,
+            category: document.category,
+            opendirectory: document.opendirectory,
+        })
+        .collect();
+
+    let pages = ((hits.total + search::PAGE_SIZE - 1) / search::PAGE_SIZE).min(search::MAX_PAGE);
+    let query_string = |page: u64| {
+        let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+        let text = [
+            ("q", &query.q),
+            ("extension", &query.extension),
+            ("category", &query.category),
+            ("host", &query.host),
+        ];
+        for (key, value) in text.iter() {
+            if let Some(value) = value {
+                serializer.append_pair(key, value);
+            }
+        }
+        let numbers = [("min_size", query.min_size), ("max_size", query.max_size)];
+        for (key, value) in numbers.iter() {
+            if let Some(value) = value {
+                serializer.append_pair(key, &value.to_string());
+            }
+        }
+        serializer.append_pair("page", &page.to_string());
+        serializer.finish()
+    };
+    Ok(Json(SearchResults {
+        previous: Some(page - 1).filter(|p| *p >= 1).map(query_string),
+        next: Some(page + 1).filter(|p| *p <= pages).map(query_string),
+        query,
+        total: hits.total,
+        page,
+        pages,
+        results,
+        categories: Category::ALL.to_vec(),
+    }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/search?<q>&<extension>&<category>&<host>&<min_size>&<max_size>&<page>")]
+async fn search(
+    index: State<'_, Box<dyn search::Searcher>>,
+    liveness: State<'_, Box<dyn search::Liveness>>,
+    q: Option<String>,
+    extension: Option<String>,
+    category: Option<String>,
+    host: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    page: Option<u64>,
+) -> Result<Template, Debug<anyhow::Error>> {
+    let results = search_json(
+        index, liveness, q, extension, category, host, min_size, max_size, page,
+    )
+    .await?
+    .into_inner();
+    Ok(Template::render("search", &results))
+}
+
 #[rocket::launch]
 async fn launch() -> Rocket {
+    let opt = Opt::from_args();
+    let db = db::Database::new().await.unwrap();
+    let liveness: Box<dyn search::Liveness> = Box::new(db.clone());
     rocket::ignite()
         .mount(
             "/",
-            routes![stats_json, stats, ods_json, ods, links_json, links],
+            routes![
+                stats_json,
+                stats,
+                ods_json,
+                ods,
+                links_json,
+                links,
+                search_json,
+                search
+            ],
         )
         .attach(Template::fairing())
-        .manage(db::Database::new().await.unwrap())
+        .manage(db)
+        .manage(search::open(&opt).unwrap())
+        .manage(liveness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+    use std::sync::{Arc, Mutex};
+
+    /// Returns `total` hits with the given documents, recording what was searched for.
+    struct Stub {
+        total: u64,
+        /// OD and alive flag of each document
+        documents: Vec<(&'static str, bool)>,
+        searches: Arc<Mutex<Vec<(search::Query, u64)>>>,
+    }
+
+    #[rocket::async_trait]
+    impl search::Searcher for Stub {
+        async fn search(&self, query: &search::Query, page: u64) -> anyhow::Result<search::Hits> {
+            self.searches.lock().unwrap().push((query.clone(), page));
+            let documents = self
+                .documents
+                .iter()
+                .enumerate()
+                .map(|(i, (od, alive))| search::Document {
+                    url: format!("{}file{}.mp4", od, i),
+                    filename: format!("file{}.mp4", i),
+                    opendirectory: od.to_string(),
+                    size: Some(1024),
+                    category: Some("video".to_string()),
+                    alive: *alive,
+                })
+                .collect();
+            Ok(search::Hits {
+                total: self.total,
+                documents,
+            })
+        }
+    }
+
+    struct DeadOds(HashSet<String>);
+
+    #[rocket::async_trait]
+    impl search::Liveness for DeadOds {
+        async fn dead(&self, ods: &HashSet<String>) -> anyhow::Result<HashSet<String>> {
+            Ok(ods.intersection(&self.0).cloned().collect())
+        }
+    }
+
+    async fn client(stub: Stub, dead: &[&str]) -> Client {
+        let searcher: Box<dyn search::Searcher> = Box::new(stub);
+        let liveness: Box<dyn search::Liveness> =
+            Box::new(DeadOds(dead.iter().map(|od| od.to_string()).collect()));
+        let rocket = rocket::ignite()
+            .mount("/", routes![search_json, search])
+            .attach(Template::fairing())
+            .manage(searcher)
+            .manage(liveness);
+        Client::tracked(rocket).await.unwrap()
+    }
+
+    fn stub(total: u64, documents: Vec<(&'static str, bool)>) -> Stub {
+        Stub {
+            total,
+            documents,
+            searches: Arc::default(),
+        }
+    }
+
+    async fn get_json(client: &Client, uri: &str) -> serde_json::Value {
+        let response = client.get(uri).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        serde_json::from_str(&response.into_string().await.unwrap()).unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn filters_are_passed_on_and_kept_between_pages() {
+        let stub = stub(500, vec![]);
+        let searches = stub.searches.clone();
+        let client = client(stub, &[]).await;
+        let json = get_json(
+            &client,
+            "/search/json?q=big%20buck&extension=mp4&category=video&host=od.test\
+             &min_size=10&max_size=20&page=3",
+        )
+        .await;
+
+        let query = search::Query {
+            q: Some("big buck".to_string()),
+            extension: Some("mp4".to_string()),
+            category: Some("video".to_string()),
+            host: Some("od.test".to_string()),
+            min_size: Some(10),
+            max_size: Some(20),
+        };
+        assert_eq!(*searches.lock().unwrap(), vec![(query, 3)]);
+        let filters =
+            "q=big+buck&extension=mp4&category=video&host=od.test&min_size=10&max_size=20";
+        assert_eq!(json["previous"], format!("{}&page=2", filters));
+        assert_eq!(json["next"], format!("{}&page=4", filters));
+    }
+
+    #[rocket::async_test]
+    async fn pages_stay_in_range() {
+        let stub = stub(120, vec![]);
+        let searches = stub.searches.clone();
+        let client = client(stub, &[]).await;
+
+        let json = get_json(&client, "/search/json?q=a").await;
+        assert_eq!(
+            (json["page"].as_u64(), json["pages"].as_u64()),
+            (Some(1), Some(3))
+        );
+        assert!(json["previous"].is_null());
+        assert_eq!(json["next"], "q=a&page=2");
+
+        let json = get_json(&client, "/search/json?q=a&page=3").await;
+        assert_eq!(json["previous"], "q=a&page=2");
+        assert!(json["next"].is_null());
+
+        get_json(&client, "/search/json?page=0").await;
+        get_json(&client, "/search/json?page=100000").await;
+        let pages: Vec<u64> = searches.lock().unwrap().iter().map(|s| s.1).collect();
+        assert_eq!(pages, vec![1, 3, 1, search::MAX_PAGE]);
+    }
+
+    #[rocket::async_test]
+    async fn links_of_dead_ods_are_not_alive() {
+        let documents = vec![
+            ("http://alive.test/", true),
+            ("http://dead.test/", true),
+            ("http://alive.test/", false),
+        ];
+        let client = client(stub(3, documents), &["http://dead.test/"]).await;
+        let json = get_json(&client, "/search/json").await;
+
+        let alive: Vec<bool> = json["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| r["alive"].as_bool().unwrap())
+            .collect();
+        assert_eq!(alive, vec![true, false, false]);
+        assert_eq!(json["total"], 3);
+    }
+
+    #[rocket::async_test]
+    async fn search_page_escapes_queries() {
+        let client = client(stub(0, vec![]), &[]).await;
+        let response = client
+            .get("/search?q=%22%3E%3Cscript%3Ealert(1)%3C%2Fscript%3E")
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let html = response.into_string().await.unwrap();
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
 }
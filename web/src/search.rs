@@ -0,0 +1,180 @@
+use crate::Opt;
+use anyhow::{bail, Context, Result};
+use isahc::http::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use shared::db;
+use shared::elastic::{Config, Connection};
+use std::collections::HashSet;
+
+/// Results per page
+pub const PAGE_SIZE: u64 = 50;
+/// Elasticsearch doesn't page beyond this many results
+const MAX_RESULTS: u64 = 10_000;
+pub const MAX_PAGE: u64 = MAX_RESULTS / PAGE_SIZE;
+
+/// What to search for. Empty fields don't filter anything.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct Query {
+    /// Terms which all have to be in the file name
+    pub q: Option<String>,
+    pub extension: Option<String>,
+    pub category: Option<String>,
+    pub host: Option<String>,
+    /// Size range in bytes
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+impl Query {
+    fn to_elastic(&self, page: u64) -> serde_json::Value {
+        let mut filters = vec![];
+        for (field, value) in [
+            ("extension", &self.extension),
+            ("category", &self.category),
+            ("host", &self.host),
+        ]
+        .iter()
+        {
+            if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+                filters.push(json!({"term": {*field: value}}));
+            }
+        }
+        let mut size = serde_json::Map::new();
+        if let Some(min) = self.min_size {
+            size.insert("gte".to_string(), min.into());
+        }
+        if let Some(max) = self.max_size {
+            size.insert("lte".to_string(), max.into());
+        }
+        if !size.is_empty() {
+            filters.push(json!({"range": {"size": size}}));
+        }
+        let must = match self.q.as_deref().filter(|q| !q.trim().is_empty()) {
+            Some(q) => json!({"match": {"filename": {"query": q, "operator": "and"}}}),
+            None => json!({"match_all": {}}),
+        };
+        json!({
+            "from": (page - 1) * PAGE_SIZE,
+            "size": PAGE_SIZE,
+            "track_total_hits": true,
+            "query": {"bool": {"must": must, "filter": filters}}
+        })
+    }
+}
+
+/// A search index document, as written by the crawler.
+#[derive(Debug, Deserialize)]
+pub struct Document {
+    pub url: String,
+    pub filename: String,
+    pub opendirectory: String,
+    pub size: Option<i64>,
+    pub category: Option<String>,
+    pub alive: bool,
+}
+
+#[derive(Debug)]
+pub struct Hits {
+    pub total: u64,
+    pub documents: Vec<Document>,
+}
+
+#[derive(Deserialize)]
+struct SearchResponse {
+    hits: SearchHits,
+}
+
+#[derive(Deserialize)]
+struct SearchHits {
+    total: Total,
+    hits: Vec<Hit>,
+}
+
+#[derive(Deserialize)]
+struct Total {
+    value: u64,
+}
+
+#[derive(Deserialize)]
+struct Hit {
+    #[serde(rename = "_source")]
+    source: Document,
+}
+
+/// Somewhere links can be searched.
+#[rocket::async_trait]
+pub trait Searcher: Send + Sync {
+    /// Returns a page of results, starting at 1.
+    async fn search(&self, query: &Query, page: u64) -> Result<Hits>;
+}
+
+/// Searches the links alias the crawler exports to.
+pub struct Elastic {
+    connection: Connection,
+    alias: String,
+}
+
+impl Elastic {
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(Self {
+            connection: Connection::new(config)?,
+            alias: config.index.clone(),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Searcher for Elastic {
+    async fn search(&self, query: &Query, page: u64) -> Result<Hits> {
+        let body = query.to_elastic(page.clamp(1, MAX_PAGE)).to_string();
+        let path = format!("{}/_search", self.alias);
+        let (status, text) = self.connection.request(Method::POST, &path, body).await?;
+        if !status.is_success() {
+            bail!("Search failed: {} {}", status, text);
+        }
+        let response: SearchResponse = serde_json::from_str(&text)
+            .with_context(|| format!("Invalid search response: {}", text))?;
+        Ok(Hits {
+            total: response.hits.total.value,
+            documents: response.hits.hits.into_iter().map(|h| h.source).collect(),
+        })
+    }
+}
+
+/// Searches whichever index the crawler is configured to export to.
+pub fn open(opt: &Opt) -> Result<Box<dyn Searcher>> {
+    Ok(match opt.search_backend.as_str() {
+        "elastic" => Box::new(Elastic::new(&opt.elastic)?),
+        #[cfg(feature = "tantivy")]
+        "tantivy" => Box::new(crate::tantivy_search::Tantivy::new(&opt.tantivy_dir)),
+        #[cfg(not(feature = "tantivy"))]
+        "tantivy" => bail!("The tantivy search backend requires building with --features tantivy"),
+        other => bail!("Unknown search backend '{}'", other),
+    })
+}
+
+/// Tells which ODs are dead. The index only knows whether they were alive when their links
+/// were exported.
+#[rocket::async_trait]
+pub trait Liveness: Send + Sync {
+    /// The dead ones among `ods`, including those which don't exist anymore.
+    async fn dead(&self, ods: &HashSet<String>) -> Result<HashSet<String>>;
+}
+
+#[rocket::async_trait]
+impl Liveness for db::Database {
+    async fn dead(&self, ods: &HashSet<String>) -> Result<HashSet<String>> {
+        // The crawler may have changed the threshold since this process started
+        let db = self.refreshed().await?;
+        let urls: Vec<&str> = ods.iter().map(String::as_str).collect();
+        let alive: HashSet<String> = db
+            .get_opendirectories_by_urls(&urls)
+            .await?
+            .into_iter()
+            .filter(|od| !od.is_dead(db.dead_od_threshold))
+            .map(|od| od.url)
+            .collect();
+        Ok(ods.difference(&alive).cloned().collect())
+    }
+}
@@ -0,0 +1,154 @@
+use crate::search::{Document, Hits, Query, Searcher, PAGE_SIZE};
+use anyhow::{Context, Result};
+use std::convert::TryFrom;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{AllQuery, BooleanQuery, Occur, Query as TantivyQuery, RangeQuery, TermQuery};
+use tantivy::schema::{IndexRecordOption, Schema, Value};
+use tantivy::tokenizer::TokenStream;
+use tantivy::{Index, IndexReader, ReloadPolicy, TantivyDocument, Term};
+
+/// File in the index directory containing the name of the live generation
+const CURRENT_FILE: &str = "current";
+
+/// One version of the crawler's index, in its own directory.
+struct Generation {
+    name: String,
+    index: Index,
+    reader: IndexReader,
+}
+
+/// Searches the index the crawler embeds, if it uses the tantivy backend.
+///
+/// The crawler replaces the live generation when it publishes a rebuild, so the live one is
+/// looked up again for every search.
+pub struct Tantivy {
+    dir: PathBuf,
+    live: Mutex<Option<Arc<Generation>>>,
+}
+
+impl Tantivy {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            live: Mutex::new(None),
+        }
+    }
+
+    fn generation(&self) -> Result<Arc<Generation>> {
+        let current = self.dir.join(CURRENT_FILE);
+        let name = std::fs::read_to_string(&current)
+            .with_context(|| format!("Failed to read {}", current.to_string_lossy()))?;
+        let name = name.trim();
+
+        let mut live = self.live.lock().unwrap();
+        if let Some(generation) = live.as_ref().filter(|g| g.name == name) {
+            return Ok(generation.clone());
+        }
+        let path = self.dir.join(name);
+        let index = Index::open_in_dir(&path)
+            .with_context(|| format!("Failed to open {}", path.to_string_lossy()))?;
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let generation = Arc::new(Generation {
+            name: name.to_string(),
+            index,
+            reader,
+        });
+        *live = Some(generation.clone());
+        Ok(generation)
+    }
+}
+
+#[rocket::async_trait]
+impl Searcher for Tantivy {
+    async fn search(&self, query: &Query, page: u64) -> Result<Hits> {
+        let generation = self.generation()?;
+        let query = query.clone();
+        rocket::tokio::task::spawn_blocking(move || search(&generation, &query, page)).await?
+    }
+}
+
+/// Builds the same query as [Query::to_elastic] from the crawler's schema.
+fn search(generation: &Generation, query: &Query, page: u64) -> Result<Hits> {
+    let schema = generation.index.schema();
+    let mut clauses = vec![];
+
+    if let Some(q) = &query.q {
+        let filename = schema.get_field("filename")?;
+        let mut analyzer = generation.index.tokenizer_for_field(filename)?;
+        let mut tokens = analyzer.token_stream(q);
+        while tokens.advance() {
+            let term = Term::from_field_text(filename, &tokens.token().text);
+            clauses.push(must(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+    }
+    for (field, value) in [
+        ("extension", &query.extension),
+        ("category", &query.category),
+        ("host", &query.host),
+    ]
+    .iter()
+    {
+        if let Some(value) = value.as_deref().filter(|v| !v.is_empty()) {
+            // The crawler lowercases these
+            let term = Term::from_field_text(schema.get_field(field)?, &value.to_lowercase());
+            clauses.push(must(TermQuery::new(term, IndexRecordOption::Basic)));
+        }
+    }
+    if query.min_size.is_some() || query.max_size.is_some() {
+        let bound = |size: Option<u64>| match size {
+            Some(size) => Bound::Included(i64::try_from(size).unwrap_or(i64::MAX)),
+            None => Bound::Unbounded,
+        };
+        clauses.push(must(RangeQuery::new_i64_bounds(
+            "size".to_string(),
+            bound(query.min_size),
+            bound(query.max_size),
+        )));
+    }
+
+    let searcher = generation.reader.searcher();
+    let collector = (
+        TopDocs::with_limit(PAGE_SIZE as usize).and_offset(((page - 1) * PAGE_SIZE) as usize),
+        Count,
+    );
+    let (top, total) = if clauses.is_empty() {
+        searcher.search(&AllQuery, &collector)?
+    } else {
+        searcher.search(&BooleanQuery::new(clauses), &collector)?
+    };
+    let documents = top
+        .into_iter()
+        .map(|(_, address)| document(&schema, &searcher.doc(address)?))
+        .collect::<Result<_>>()?;
+    Ok(Hits {
+        total: total as u64,
+        documents,
+    })
+}
+
+fn must(query: impl TantivyQuery + 'static) -> (Occur, Box<dyn TantivyQuery>) {
+    (Occur::Must, Box::new(query))
+}
+
+fn document(schema: &Schema, doc: &TantivyDocument) -> Result<Document> {
+    let text = |field| -> Result<Option<String>> {
+        let value = doc.get_first(schema.get_field(field)?);
+        Ok(value.and_then(|v| v.as_str()).map(String::from))
+    };
+    let size = doc.get_first(schema.get_field("size")?);
+    let alive = doc.get_first(schema.get_field("alive")?);
+    Ok(Document {
+        url: text("url")?.unwrap_or_default(),
+        filename: text("filename")?.unwrap_or_default(),
+        opendirectory: text("opendirectory")?.unwrap_or_default(),
+        size: size.and_then(|v| v.as_i64()),
+        category: text("category")?,
+        alive: alive.and_then(|v| v.as_bool()).unwrap_or(true),
+    })
+}